@@ -4,16 +4,21 @@ use std::fs::File;
 use std::io::{BufWriter, Read};
 
 use libfri::encoder::{EncoderOpts, FRIEncoder};
-use libfri::decoder::FRIDecoder;
+use libfri::decoder::{DecoderOpts, FRIDecoder};
+
+use crate::commands::encode::Variant;
 
 #[derive(clap::Args)]
 pub struct BenchCommand {
     pub dataset_path: PathBuf,
 
+    /// Fractal tiling used by the wavelet transform
+    #[arg(long, value_enum, default_value_t = Variant::TameTwindragon)]
+    pub variant: Variant,
 }
 
 pub fn benchmark(cmd: BenchCommand) {
-    let paths = fs::read_dir(cmd.dataset_path).expect(&format!("No such directory"));
+    let paths = fs::read_dir(&cmd.dataset_path).expect("No such directory");
     fs::create_dir_all("./output").unwrap(); 
     let mut compression_rates: Vec<f32> = vec![];
     let mut compression_rates_png: Vec<f32> = vec![];
@@ -31,7 +36,11 @@ pub fn benchmark(cmd: BenchCommand) {
         println!("======================================");
         let png_size = fs::metadata(&img_path).unwrap().len();
         println!("PNG size: {}", png_size);
-        let encoder = FRIEncoder::new(EncoderOpts::default());
+        let encoder = FRIEncoder::new(EncoderOpts {
+            variant: cmd.variant.clone().into(),
+            ..Default::default()
+        })
+        .unwrap_or_else(|e| panic!("Cannot create encoder: {e}"));
 
         let height = img.height();
         let width = img.width();
@@ -67,11 +76,12 @@ pub fn benchmark(cmd: BenchCommand) {
             fs::write(&img_path, &result).unwrap_or_else(|e| panic!("Failed to encode frv image: {e}"));
         }
 
-        let decoder = FRIDecoder{};
+        let decoder = FRIDecoder::new(DecoderOpts::default())
+            .unwrap_or_else(|e| panic!("Cannot create decoder: {e}"));
 
         match decoder.decode(result) {
             Ok(decoded) => {
-               let img: image::RgbImage = match image::ImageBuffer::from_vec(decoded.metadata.width as u32, decoded.metadata.height as u32, decoded.data) {
+               let img: image::RgbImage = match image::ImageBuffer::from_vec(decoded.metadata.width, decoded.metadata.height, decoded.data) {
                     Some(buf) => buf,
                     None => {
                         eprintln!("Failed to create image buffer.");
@@ -83,7 +93,7 @@ pub fn benchmark(cmd: BenchCommand) {
                 output_path.push(img_path.file_name().unwrap());
                 output_path.set_extension("bmp");
                 let file = File::create(&output_path).unwrap();
-                let ref mut w = BufWriter::new(file);
+                let w = &mut BufWriter::new(file);
 
                 img.write_to(w, image::ImageOutputFormat::Bmp).expect("Failed to write image");
 
@@ -95,7 +105,7 @@ pub fn benchmark(cmd: BenchCommand) {
                 let len = original_img.len() as f32;
 
                 let mut mse: u32 = 0;
-                for (x, y) in decoded_img.into_iter().zip(original_img.into_iter()) {
+                for (x, y) in decoded_img.into_iter().zip(original_img) {
                     mse += (x.unwrap() - y).pow(2) as u32;
                 }
                 println!("MSE: {}", mse as f32/len);
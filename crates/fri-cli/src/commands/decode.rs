@@ -1,10 +1,12 @@
 use image;
-use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::fs::File;
-use std::io::BufWriter;
+use std::io::{BufReader, BufWriter};
 
-use libfri::decoder::FRIDecoder;
+use libfri::decoder::{DecoderOpts, FRIDecoder, DEFAULT_MAX_PIXELS};
+use libfri::images::{RasterImage, Region};
+
+use crate::metadata;
 
 
 #[derive(clap::Args)]
@@ -12,32 +14,116 @@ use libfri::decoder::FRIDecoder;
 pub struct DecodeCommand {
     pub fr_path: PathBuf,
 
+    /// Output path, ICC profile, EXIF and XMP are kept only when writing PNG. Frames of a
+    /// sequence get a numbered suffix
     #[arg(short, default_value_t = String::from("a.bmp"))]
     pub output: String,
+
+    /// Decode only up to this fractal level to get a fast preview
+    #[arg(short, long)]
+    pub level: Option<u8>,
+
+    /// Decode only the given rectangle of the image
+    #[arg(long, num_args = 4, value_names = ["X", "Y", "WIDTH", "HEIGHT"])]
+    pub region: Option<Vec<u32>>,
+
+    /// Number of decoder threads, all available cores are used by default
+    #[arg(short = 'j', long, default_value_t = 0)]
+    pub threads: usize,
+
+    /// Refuse to decode images with more pixels than this
+    #[arg(long, default_value_t = DEFAULT_MAX_PIXELS)]
+    pub max_pixels: u64,
+
+    /// Skip verification of segment checksums
+    #[arg(long, default_value_t = false)]
+    pub no_verify: bool,
 }
 
 pub fn decode_image(cmd: DecodeCommand) {
-    let data = fs::read(cmd.fr_path).unwrap_or_else(|e| {
+    let file = File::open(cmd.fr_path).unwrap_or_else(|e| {
         panic!("Failed to open: {e}");
     });
 
-    let decoder = FRIDecoder{};
+    let region = cmd.region.map(|r| Region { x: r[0], y: r[1], width: r[2], height: r[3] });
+    let decoder = match FRIDecoder::new(DecoderOpts {
+        max_level: cmd.level,
+        region,
+        threads: cmd.threads,
+        max_pixels: Some(cmd.max_pixels),
+        verify_checksums: !cmd.no_verify,
+    }) {
+        Ok(decoder) => decoder,
+        Err(msg) => {
+            println!("Cannot decode, reason: {msg}");
+            return;
+        }
+    };
+
+    let frames = match decoder.decode_frames(BufReader::new(file)) {
+        Ok(frames) => frames,
+        Err(msg) => {
+            println!("Cannot decode, reason: {msg}");
+            return;
+        }
+    };
 
-    match decoder.decode(data) {
-        Ok(result) => {
-           let img: image::RgbImage = match image::ImageBuffer::from_vec(result.metadata.width as u32, result.metadata.height as u32, result.data) {
-                Some(buf) => buf,
-                None => {
-                    eprintln!("Failed to create image buffer.");
-                    return;
-                }
-            };
+    /* Frames of a sequence are written next to each other as <name>_0000.<ext>, ... */
+    let mut frames = frames.peekable();
+    let mut index = 0;
+    while let Some(frame) = frames.next() {
+        let image = match frame {
+            Ok(frame) => frame.image,
+            Err(msg) => {
+                println!("Cannot decode, reason: {msg}");
+                return;
+            }
+        };
+        let output = match index == 0 && frames.peek().is_none() {
+            true => PathBuf::from(&cmd.output),
+            false => frame_path(Path::new(&cmd.output), index),
+        };
+        write_image(&output, image);
+        index += 1;
+    }
+}
 
-            let file = File::create(cmd.output).unwrap();
-            let ref mut w = BufWriter::new(file);
+fn frame_path(output: &Path, index: usize) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match output.extension() {
+        Some(ext) => format!("{stem}_{index:04}.{}", ext.to_string_lossy()),
+        None => format!("{stem}_{index:04}"),
+    };
+    output.with_file_name(name)
+}
 
-            img.write_to(w, image::ImageOutputFormat::Bmp).expect("Failed to write image");
+fn write_image(output: &Path, result: RasterImage) {
+    if output.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png")) {
+        let metadata = &result.metadata;
+        let num_channels = metadata.colorspace.num_channels();
+        if let Err(e) = metadata::write_png(
+            output,
+            metadata.width,
+            metadata.height,
+            num_channels,
+            &result.data,
+            &metadata.embedded,
+        ) {
+            eprintln!("Failed to write image: {e}");
         }
-        Err(msg) => println!("Cannot decode, reason: {msg}"),
+        return;
     }
+
+    let img: image::RgbImage = match image::ImageBuffer::from_vec(result.metadata.width, result.metadata.height, result.data) {
+        Some(buf) => buf,
+        None => {
+            eprintln!("Failed to create image buffer.");
+            return;
+        }
+    };
+
+    let file = File::create(output).unwrap();
+    let w = &mut BufWriter::new(file);
+
+    img.write_to(w, image::ImageOutputFormat::Bmp).expect("Failed to write image");
 }
@@ -1,10 +1,65 @@
 use std::fs;
+use std::io::BufWriter;
 use std::path::PathBuf;
 
-use libfri::encoder::{EncoderOpts, FRIEncoder};
+use libfri::encoder::{EncoderOpts, EncoderQuality, FRIEncoder, QualityTarget, RateTarget};
+use libfri::images::{EntropyCoder, Frame, FractalVariant, ImageMetadata, RasterImage};
+
+use crate::{metadata, y4m};
+
+#[derive(clap::ValueEnum, Clone)]
+pub enum Quality {
+    Low,
+    Medium,
+    High,
+    Lossless,
+}
+
+impl From<Quality> for EncoderQuality {
+    fn from(quality: Quality) -> Self {
+        match quality {
+            Quality::Low => EncoderQuality::Low,
+            Quality::Medium => EncoderQuality::Medium,
+            Quality::High => EncoderQuality::High,
+            Quality::Lossless => EncoderQuality::Lossless,
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone)]
+pub enum Variant {
+    TameTwindragon,
+    Twindragon,
+    Boxes,
+}
+
+impl From<Variant> for FractalVariant {
+    fn from(variant: Variant) -> Self {
+        match variant {
+            Variant::TameTwindragon => FractalVariant::TameTwindragon,
+            Variant::Twindragon => FractalVariant::Twindragon,
+            Variant::Boxes => FractalVariant::Boxes,
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone)]
+pub enum Coder {
+    Rans,
+    Binary,
+}
+
+impl From<Coder> for EntropyCoder {
+    fn from(coder: Coder) -> Self {
+        match coder {
+            Coder::Rans => EntropyCoder::Rans,
+            Coder::Binary => EntropyCoder::Binary,
+        }
+    }
+}
 
 #[derive(clap::Args)]
-/// Encodes bitmap file to frave format
+/// Encodes bitmap file to frave format, Y4M input is encoded as a sequence of frames
 pub struct EncodeCommand {
     pub bmp_path: PathBuf,
 
@@ -13,19 +68,93 @@ pub struct EncodeCommand {
 
     #[arg(long, default_value_t = false)]
     pub emit_coefficients: bool,
+
+    /// Quality level, every level other than lossless quantizes the wavelet coefficients
+    #[arg(short = 'Q', long, value_enum, default_value_t = Quality::Lossless)]
+    pub quality: Quality,
+
+    /// Fractal tiling used by the wavelet transform
+    #[arg(long, value_enum, default_value_t = Variant::TameTwindragon)]
+    pub variant: Variant,
+
+    /// Encode to at most this many bytes, quality selects the shape of quantization matrix
+    #[arg(long, conflicts_with = "target_bpp")]
+    pub target_size: Option<usize>,
+
+    /// Encode to at most this many bits per pixel
+    #[arg(long, conflicts_with = "target_psnr")]
+    pub target_bpp: Option<f32>,
+
+    /// Encode to the smallest size that reaches this PSNR in dB
+    #[arg(long, conflicts_with_all = ["target_size", "target_ssim"])]
+    pub target_psnr: Option<f32>,
+
+    /// Encode to the smallest size that reaches this SSIM
+    #[arg(long, conflicts_with_all = ["target_size", "target_bpp"])]
+    pub target_ssim: Option<f32>,
+
+    /// Code every this many frames of a sequence without temporal prediction
+    #[arg(long, default_value_t = 0)]
+    pub keyframe_interval: usize,
+
+    /// Split the image into independently coded tiles of at most this many pixels per side
+    #[arg(long, conflicts_with_all = ["target_size", "target_bpp", "target_psnr", "target_ssim"])]
+    pub tile_size: Option<u32>,
+
+    /// Number of encoder threads, all available cores are used by default
+    #[arg(short = 'j', long, default_value_t = 0)]
+    pub threads: usize,
+
+    /// Do not store segment checksums, saves a few bytes and encoding time
+    #[arg(long, default_value_t = false)]
+    pub no_checksums: bool,
+
+    /// Adapt symbol probabilities while coding instead of storing them with the image
+    #[arg(long, default_value_t = false)]
+    pub adaptive: bool,
+
+    /// Number of contexts residuals are split into, chosen for every image by default
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=16))]
+    pub contexts: Option<usize>,
+
+    /// Entropy coder of the residuals, binary arithmetic coding is smaller and slower
+    #[arg(long, value_enum, default_value_t = Coder::Rans, conflicts_with = "adaptive")]
+    pub entropy_coder: Coder,
+
+    /// Drop ICC profile, EXIF and XMP of the input image
+    #[arg(long, default_value_t = false)]
+    pub strip_metadata: bool,
 }
 
 pub fn encode_image(cmd: EncodeCommand, verbose: bool) {
-    let img = image::open(cmd.bmp_path).unwrap_or_else(|e| {
+    if cmd.bmp_path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("y4m")) {
+        return encode_sequence(cmd, verbose);
+    }
+
+    let img = image::open(&cmd.bmp_path).unwrap_or_else(|e| {
         panic!("Failed to open: {e}");
     });
+    let embedded_metadata = match cmd.strip_metadata {
+        true => Default::default(),
+        false => metadata::read_embedded(&cmd.bmp_path),
+    };
 
     let luma_img = img;
     let encoder = FRIEncoder::new(EncoderOpts {
         emit_coefficients: cmd.emit_coefficients,
+        quality: cmd.quality.into(),
+        variant: cmd.variant.into(),
+        tile_size: cmd.tile_size,
+        threads: cmd.threads,
+        checksums: !cmd.no_checksums,
+        adaptive: cmd.adaptive,
+        contexts: cmd.contexts,
+        entropy_coder: cmd.entropy_coder.into(),
+        embedded_metadata,
         verbose: true,
         ..Default::default() 
-    });
+    })
+    .unwrap_or_else(|e| panic!("Cannot create encoder: {e}"));
 
     let height = luma_img.height();
     let width = luma_img.width();
@@ -39,7 +168,32 @@ pub fn encode_image(cmd: EncodeCommand, verbose: bool) {
     };
     let uncompressed_size = data.len();
 
-    match encoder.encode(data, height, width, frifcolor) {
+    let rate_target = match (cmd.target_size, cmd.target_bpp) {
+        (Some(bytes), _) => Some(RateTarget::Bytes(bytes)),
+        (_, Some(bpp)) => Some(RateTarget::BitsPerPixel(bpp)),
+        _ => None,
+    };
+
+    let quality_target = match (cmd.target_psnr, cmd.target_ssim) {
+        (Some(psnr), _) => Some(QualityTarget::Psnr(psnr)),
+        (_, Some(ssim)) => Some(QualityTarget::Ssim(ssim)),
+        _ => None,
+    };
+
+    let encoded = match (rate_target, quality_target) {
+        (Some(target), _) => encoder.encode_to_rate(data, height, width, frifcolor, target),
+        (_, Some(target)) => encoder
+            .encode_to_quality(data, height, width, frifcolor, target)
+            .map(|result| {
+                if verbose {
+                    println!("Achieved quality: {}", result.achieved);
+                }
+                result.data
+            }),
+        _ => encoder.encode(data, height, width, frifcolor),
+    };
+
+    match encoded {
         Ok(result) => {
             if verbose {
                 println!("Before compression size: {}", uncompressed_size);
@@ -52,3 +206,41 @@ pub fn encode_image(cmd: EncodeCommand, verbose: bool) {
         Err(msg) => eprintln!("Cannot encode, reason: {msg}"),
     }
 }
+
+fn encode_sequence(cmd: EncodeCommand, verbose: bool) {
+    let sequence = y4m::read(&cmd.bmp_path).unwrap_or_else(|e| {
+        panic!("Failed to open: {e}");
+    });
+    let (width, height) = (sequence.width, sequence.height);
+    let frame_count = sequence.frames.len();
+    let frames = sequence.frames.into_iter().map(|data| Frame {
+        image: RasterImage { metadata: ImageMetadata::new(height, width), data },
+        duration_ms: sequence.frame_duration_ms,
+    });
+
+    let encoder = FRIEncoder::new(EncoderOpts {
+        quality: cmd.quality.into(),
+        variant: cmd.variant.into(),
+        threads: cmd.threads,
+        checksums: !cmd.no_checksums,
+        adaptive: cmd.adaptive,
+        contexts: cmd.contexts,
+        entropy_coder: cmd.entropy_coder.into(),
+        keyframe_interval: cmd.keyframe_interval,
+        ..Default::default()
+    })
+    .unwrap_or_else(|e| panic!("Cannot create encoder: {e}"));
+
+    let file = fs::File::create(&cmd.output).unwrap_or_else(|e| panic!("Failed to create {}: {e}", cmd.output));
+    match encoder.encode_sequence(frames, BufWriter::new(file)) {
+        Ok(()) => {
+            if verbose {
+                let compressed_size = fs::metadata(&cmd.output).map(|m| m.len()).unwrap_or(0);
+                println!("Frames: {}", frame_count);
+                println!("Before compression size: {}", frame_count * (width * height * 3) as usize);
+                println!("After compression size: {}", compressed_size);
+            }
+        }
+        Err(msg) => eprintln!("Cannot encode, reason: {msg}"),
+    }
+}
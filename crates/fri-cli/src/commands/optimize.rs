@@ -1,10 +1,8 @@
-use core::fmt;
 use std::fs;
 use std::fs::File;
-use std::io::{BufWriter, BufRead, BufReader, Read};
+use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
 
-use libfri::decoder::FRIDecoder;
 use libfri::encoder::{EncoderOpts, FRIEncoder};
 
 #[derive(clap::Args)]
@@ -32,14 +30,10 @@ fn find_arrays(arr: &mut Vec<f32>, n: usize, sum: f32, results: &mut Vec<Vec<f32
 }
 
 pub fn optimize(cmd: OptimizeCommand) {
-    let paths = fs::read_dir(cmd.dataset_path).expect(&format!("No such directory"));
+    let paths = fs::read_dir(cmd.dataset_path).expect("No such directory");
     fs::create_dir_all("./output").unwrap();
     let mut lowest_mse = f32::MAX;
 
-    let coef_set = [
-        [1./6., 1./6., 1./6., 1./6., 1./6., 1./6.],
-    ];
-
     let n = 6;
     let target_sum = 6.;
 
@@ -51,12 +45,12 @@ pub fn optimize(cmd: OptimizeCommand) {
     find_arrays(&mut current_vec, n, target_sum, &mut results, min_val, max_val);
 
     for arr in results.iter_mut() {
-        arr[0] = arr[0] / 6.;
-        arr[1] = arr[1] / 6.;
-        arr[2] = arr[2] / 6.;
-        arr[3] = arr[3] / 6.;
-        arr[4] = arr[4] / 6.;
-        arr[5] = arr[5] / 6.;
+        arr[0] /= 6.;
+        arr[1] /= 6.;
+        arr[2] /= 6.;
+        arr[3] /= 6.;
+        arr[4] /= 6.;
+        arr[5] /= 6.;
     }
     //dbg!(results);
 
@@ -74,11 +68,22 @@ pub fn optimize(cmd: OptimizeCommand) {
 
             let encoder = FRIEncoder::new(EncoderOpts {
                 quality: libfri::encoder::EncoderQuality::Lossless,
+                variant: libfri::images::FractalVariant::TameTwindragon,
+                quantization_scale: 1.,
+                tile_size: None,
+                threads: 0,
+                checksums: true,
+                adaptive: false,
+                contexts: None,
+                entropy_coder: Default::default(),
+                embedded_metadata: Default::default(),
+                keyframe_interval: 0,
                 emit_coefficients: false,
                 verbose: false,
                 value_prediction_params: Default::default(), 
                 width_prediction_params: Default::default()
-            });
+            })
+            .unwrap_or_else(|e| panic!("Cannot create encoder: {e}"));
 
             let height = img.height();
             let width = img.width();
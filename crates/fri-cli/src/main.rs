@@ -1,4 +1,6 @@
 pub mod commands;
+pub mod metadata;
+pub mod y4m;
 
 use clap::Parser;
 use commands::{bench, decode, encode, optimize};
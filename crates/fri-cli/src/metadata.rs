@@ -0,0 +1,92 @@
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+
+use libfri::images::EmbeddedMetadata;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const PNG_XMP_KEYWORD: &str = "XML:com.adobe.xmp";
+
+/*
+ * Reads ICC profile, EXIF and XMP of PNG and JPEG files, the image crate does
+ * not expose them so both formats are parsed here once more.
+ */
+pub fn read_embedded(path: &Path) -> EmbeddedMetadata {
+    match fs::read(path) {
+        Ok(bytes) if bytes.starts_with(PNG_SIGNATURE) => read_png(&bytes),
+        Ok(bytes) if bytes.starts_with(&[0xFF, 0xD8]) => read_jpeg(&bytes),
+        _ => EmbeddedMetadata::default(),
+    }
+}
+
+fn read_png(bytes: &[u8]) -> EmbeddedMetadata {
+    let mut embedded = EmbeddedMetadata::default();
+    if let Ok(reader) = png::Decoder::new(bytes).read_info() {
+        let info = reader.info();
+        embedded.icc_profile = info.icc_profile.as_ref().map(|profile| profile.to_vec());
+        embedded.xmp = info
+            .utf8_text
+            .iter()
+            .find(|chunk| chunk.keyword == PNG_XMP_KEYWORD)
+            .and_then(|chunk| chunk.get_text().ok())
+            .map(String::into_bytes);
+    }
+
+    // Decoder of the png crate skips eXIf chunks, so they are looked up directly
+    let mut position = PNG_SIGNATURE.len();
+    while position + 8 <= bytes.len() {
+        let len = u32::from_be_bytes(bytes[position..position + 4].try_into().unwrap()) as usize;
+        let kind = &bytes[position + 4..position + 8];
+        let Some(data) = bytes.get(position + 8..position + 8 + len) else {
+            break;
+        };
+        if kind == b"eXIf" {
+            embedded.exif = Some(data.to_vec());
+            break;
+        }
+        position += 12 + len;
+    }
+    embedded
+}
+
+fn read_jpeg(bytes: &[u8]) -> EmbeddedMetadata {
+    let mut decoder = jpeg_decoder::Decoder::new(bytes);
+    if decoder.read_info().is_err() {
+        return EmbeddedMetadata::default();
+    }
+    EmbeddedMetadata {
+        icc_profile: decoder.icc_profile(),
+        exif: decoder.exif_data().map(<[u8]>::to_vec),
+        xmp: decoder.xmp_data().map(<[u8]>::to_vec),
+    }
+}
+
+/*
+ * Writes 8 bit RGB or grayscale pixels as PNG, the only output format keeping
+ * all of the embedded metadata.
+ */
+pub fn write_png(
+    path: &Path,
+    width: u32,
+    height: u32,
+    num_channels: usize,
+    data: &[u8],
+    embedded: &EmbeddedMetadata,
+) -> Result<(), png::EncodingError> {
+    let mut info = png::Info::with_size(width, height);
+    info.color_type = match num_channels {
+        1 => png::ColorType::Grayscale,
+        _ => png::ColorType::Rgb,
+    };
+    info.bit_depth = png::BitDepth::Eight;
+    info.icc_profile = embedded.icc_profile.as_deref().map(Cow::Borrowed);
+    info.exif_metadata = embedded.exif.as_deref().map(Cow::Borrowed);
+
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::with_info(BufWriter::new(file), info)?;
+    if let Some(xmp) = &embedded.xmp {
+        encoder.add_itxt_chunk(String::from(PNG_XMP_KEYWORD), String::from_utf8_lossy(xmp).into_owned())?;
+    }
+    encoder.write_header()?.write_image_data(data)
+}
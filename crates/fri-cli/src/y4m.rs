@@ -0,0 +1,99 @@
+use std::fs;
+use std::path::Path;
+
+/*
+ * Frames of a YUV4MPEG2 stream converted to 8 bit RGB.
+ */
+pub struct Y4mSequence {
+    pub width: u32,
+    pub height: u32,
+    pub frame_duration_ms: u32,
+    pub frames: Vec<Vec<u8>>,
+}
+
+/*
+ * Reads 8 bit 4:2:0, 4:2:2, 4:4:4 and monochrome streams. Samples are taken as
+ * limited range BT.601 and chroma planes are upsampled by repeating samples.
+ */
+pub fn read(path: &Path) -> Result<Y4mSequence, String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    let header_end = bytes.iter().position(|&b| b == b'\n').ok_or("missing Y4M header")?;
+    let header = std::str::from_utf8(&bytes[..header_end]).map_err(|e| e.to_string())?;
+    let mut tokens = header.split(' ');
+    if tokens.next() != Some("YUV4MPEG2") {
+        return Err(String::from("missing YUV4MPEG2 signature"));
+    }
+
+    let (mut width, mut height) = (0, 0);
+    let mut frame_duration_ms = 40;
+    let mut chroma = "420";
+    for token in tokens {
+        let (key, value) = token.split_at(1);
+        match key {
+            "W" => width = value.parse().map_err(|_| "invalid width")?,
+            "H" => height = value.parse().map_err(|_| "invalid height")?,
+            "F" => {
+                let (num, den) = value.split_once(':').ok_or("invalid frame rate")?;
+                let num: u64 = num.parse().map_err(|_| "invalid frame rate")?;
+                let den: u64 = den.parse().map_err(|_| "invalid frame rate")?;
+                if let Some(duration) = (1000 * den + num / 2).checked_div(num) {
+                    frame_duration_ms = duration as u32;
+                }
+            }
+            "C" => chroma = value,
+            _ => {}
+        }
+    }
+
+    let (sub_x, sub_y) = match chroma {
+        "420" | "420jpeg" | "420paldv" | "420mpeg2" => (2, 2),
+        "422" => (2, 1),
+        "444" => (1, 1),
+        "mono" => (0, 0),
+        other => return Err(format!("unsupported Y4M chroma format {other}")),
+    };
+    if width == 0 || height == 0 {
+        return Err(String::from("missing Y4M dimensions"));
+    }
+
+    let (w, h) = (width as usize, height as usize);
+    let (chroma_w, chroma_h) = match sub_x {
+        0 => (0, 0),
+        _ => (w.div_ceil(sub_x), h.div_ceil(sub_y)),
+    };
+    let frame_len = w * h + 2 * chroma_w * chroma_h;
+
+    let mut frames = vec![];
+    let mut position = header_end + 1;
+    while position < bytes.len() {
+        if !bytes[position..].starts_with(b"FRAME") {
+            return Err(String::from("missing FRAME marker"));
+        }
+        let line_end = bytes[position..].iter().position(|&b| b == b'\n').ok_or("truncated Y4M frame")?;
+        position += line_end + 1;
+        let planes = bytes.get(position..position + frame_len).ok_or("truncated Y4M frame")?;
+        position += frame_len;
+
+        let (luma, chroma_planes) = planes.split_at(w * h);
+        let (cb, cr) = chroma_planes.split_at(chroma_w * chroma_h);
+        let mut rgb = Vec::with_capacity(w * h * 3);
+        for y in 0..h {
+            for x in 0..w {
+                let (u, v) = match sub_x {
+                    0 => (128, 128),
+                    _ => {
+                        let index = (y / sub_y) * chroma_w + x / sub_x;
+                        (cb[index] as i32, cr[index] as i32)
+                    }
+                };
+                let (c, d, e) = (luma[y * w + x] as i32 - 16, u - 128, v - 128);
+                rgb.push(((298 * c + 409 * e + 128) >> 8).clamp(0, 255) as u8);
+                rgb.push(((298 * c - 100 * d - 208 * e + 128) >> 8).clamp(0, 255) as u8);
+                rgb.push(((298 * c + 516 * d + 128) >> 8).clamp(0, 255) as u8);
+            }
+        }
+        frames.push(rgb);
+    }
+
+    Ok(Y4mSequence { width, height, frame_duration_ms, frames })
+}
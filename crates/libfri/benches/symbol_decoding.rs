@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use libfri::bench::{AnsContext, EntropyBackend, RansBackend, ResidualDecoder, ResidualEncoder, ALPHABET_SIZE};
+
+const SYMBOLS: usize = 1 << 16;
+const WIDTH: f32 = 4.;
+
+/*
+ * Roughly Laplacian residuals from a fixed pseudo random sequence.
+ */
+fn get_residuals() -> Vec<u32> {
+    (0..SYMBOLS as u64)
+        .map(|i| (i.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 40) as f32 / (1 << 24) as f32)
+        .map(|u| (-WIDTH * 2. * (1. - u).ln()) as u32)
+        .collect()
+}
+
+fn get_context(residuals: &[u32], max_freq_bits: u32) -> AnsContext {
+    let mut context = AnsContext::for_bucket(0, WIDTH);
+    for &residual in residuals {
+        context.bump_freq(residual);
+    }
+    context.max_freq_bits = max_freq_bits;
+    context.finalize_context(true);
+    context
+}
+
+/*
+ * Lookup the slot table replaced: a binary search for the cumulative frequency, a
+ * scan for the last token starting at it and a map to its decoder symbol.
+ */
+fn search_token(context: &AnsContext, decoder_symbols: &HashMap<u32, (u32, u32)>, slot: u32) -> usize {
+    let cum_freq = match context.cdf.binary_search(&slot) {
+        Ok(index) => context.cdf[index],
+        Err(index) => context.cdf[index - 1],
+    };
+    let mut token = context.cdf.iter().position(|&cdf| cdf == cum_freq).unwrap();
+    while token < ALPHABET_SIZE && context.cdf[token] == cum_freq {
+        token += 1;
+    }
+    black_box(decoder_symbols[&cum_freq]);
+    token - 1
+}
+
+fn token_lookup(c: &mut Criterion) {
+    let residuals = get_residuals();
+    let mut group = c.benchmark_group("token_lookup");
+    group.throughput(Throughput::Elements(SYMBOLS as u64));
+
+    for max_freq_bits in [12, 20] {
+        let context = get_context(&residuals, max_freq_bits);
+        let decoder_symbols: HashMap<u32, (u32, u32)> =
+            context.cdf.iter().zip(context.freqs.iter()).map(|(&cdf, &freq)| (cdf, (cdf, freq))).collect();
+        let slots: Vec<u32> = (0..SYMBOLS as u64)
+            .map(|i| (i.wrapping_mul(0xD1B5_4A32_D192_ED03) >> (64 - context.max_freq_bits)) as u32)
+            .collect();
+        for &slot in &slots {
+            assert_eq!(context.find_token(slot), search_token(&context, &decoder_symbols, slot));
+        }
+
+        let name = format!("{max_freq_bits} bit");
+        group.bench_with_input(BenchmarkId::new("cdf search", &name), &slots, |b, slots| {
+            b.iter(|| slots.iter().map(|&slot| search_token(&context, &decoder_symbols, slot)).sum::<usize>())
+        });
+        group.bench_with_input(BenchmarkId::new("slot table", &name), &slots, |b, slots| {
+            b.iter(|| slots.iter().map(|&slot| context.find_token(slot)).sum::<usize>())
+        });
+    }
+    group.finish();
+}
+
+fn rans_decoding(c: &mut Criterion) {
+    let residuals = get_residuals();
+    let mut group = c.benchmark_group("rans_decoding");
+    group.throughput(Throughput::Elements(SYMBOLS as u64));
+
+    let cases = [
+        ("12 bit", get_context(&residuals, 12)),
+        ("20 bit", get_context(&residuals, 20)),
+        ("adaptive", AnsContext::adaptive_prior(0, WIDTH)),
+    ];
+    for (name, context) in cases {
+        let contexts = [context];
+        let mut encoder = RansBackend::encoder(&contexts);
+        for &residual in &residuals {
+            encoder.encode(0, residual);
+        }
+        let data = encoder.finish();
+
+        group.bench_with_input(BenchmarkId::from_parameter(name), &data, |b, data| {
+            b.iter(|| {
+                let mut decoder = RansBackend::decoder(&contexts, data.clone());
+                (0..SYMBOLS).map(|_| decoder.decode(0)).sum::<u32>()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, token_lookup, rans_decoding);
+criterion_main!(benches);
@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfri::decoder::{DecoderOpts, FRIDecoder};
+use libfuzzer_sys::fuzz_target;
+
+/* Inputs that crashed the decoder are kept in regressions/ and checked by the libfri tests */
+fuzz_target!(|data: &[u8]| {
+    /* Bounded image size keeps single runs fast, any input has to end in Ok or Err */
+    let decoder = FRIDecoder::new(DecoderOpts {
+        threads: 1,
+        max_pixels: Some(1 << 20),
+        ..Default::default()
+    });
+    let _ = decoder.and_then(|decoder| decoder.decode(data.to_vec()));
+});
@@ -0,0 +1,7 @@
+/*
+ * Internals measured by the benchmarks in benches/, only built with the bench
+ * feature.
+ */
+pub use crate::stages::entropy_coding::{
+    AnsContext, EntropyBackend, RansBackend, ResidualDecoder, ResidualEncoder, ALPHABET_SIZE,
+};
@@ -4,6 +4,7 @@ use lstsq::lstsq;
 use nalgebra::Dynamic;
 use nalgebra::{self as na, DMatrix, DVector, U2};
 use num::Complex;
+use rayon::prelude::*;
 
 use crate::stages::wavelet_transform::Fractal;
 use crate::stages::wavelet_transform::WaveletImage;
@@ -22,30 +23,35 @@ impl ContextModeler {
         }
     }
 
-    pub fn get_neighbour_values(
+    pub fn get_neighbour_values<C: AsRef<[Option<i32>]>>(
         image_position: Complex<i32>,
         current_depth: u8,
         parent_fractal_pos: &Complex<i32>,
         fractal_lattice: &HashMap<Complex<i32>, Fractal>,
+        coefficients: &HashMap<Complex<i32>, C>,
         global_position_map: &Vec<HashMap<Complex<i32>, Complex<i32>>>,
-        channel: usize,
     ) -> Vec<i32> {
         assert!(current_depth > 0);
         let level = current_depth as usize;
         let fractal = &fractal_lattice[parent_fractal_pos];
 
+        // Neighbours from other fractal groups are treated as missing, which keeps
+        // every group decodable on its own
         let same_level_values: Vec<i32> = vec![
-            Fractal::get_left(image_position, fractal.depth - level as u8, global_position_map),
-            Fractal::get_up_left(image_position, fractal.depth - level as u8, global_position_map),
-            Fractal::get_up_right(image_position, fractal.depth - level as u8, global_position_map),
+            fractal.get_left(image_position, fractal.depth - level as u8, global_position_map),
+            fractal.get_up_left(image_position, fractal.depth - level as u8, global_position_map),
+            fractal.get_up_right(image_position, fractal.depth - level as u8, global_position_map),
         ]
         .iter()
         .map(|pos| {
             if let Some(parent_fractal_loc) = global_position_map[level].get(pos)
             {
                 let containing_fractal = &fractal_lattice[parent_fractal_loc];
+                if containing_fractal.group != fractal.group {
+                    return 0;
+                }
                 let haar_pos = containing_fractal.position_map[level][pos];
-                containing_fractal.coefficients[channel][haar_pos].unwrap_or(0)
+                coefficients[parent_fractal_loc].as_ref()[haar_pos].unwrap_or(0)
             } else {
                 0
             }
@@ -53,17 +59,20 @@ impl ContextModeler {
         .collect();
 
         let above_level_values: Vec<i32> = vec![
-            Fractal::get_right(image_position, fractal.depth - level as u8, global_position_map),
-            Fractal::get_down_left(image_position, fractal.depth - level as u8, global_position_map),
-            Fractal::get_down_right(image_position, fractal.depth - level as u8, global_position_map),
+            fractal.get_right(image_position, fractal.depth - level as u8, global_position_map),
+            fractal.get_down_left(image_position, fractal.depth - level as u8, global_position_map),
+            fractal.get_down_right(image_position, fractal.depth - level as u8, global_position_map),
         ]
         .iter()
         .map(|pos| {
             if let Some(parent_fractal_loc) = global_position_map[level].get(pos)
             {
                 let containing_fractal = &fractal_lattice[parent_fractal_loc];
+                if containing_fractal.group != fractal.group {
+                    return 0;
+                }
                 let haar_pos = containing_fractal.position_map[level][pos];
-                containing_fractal.coefficients[channel][haar_pos/2].unwrap_or(0)
+                coefficients[parent_fractal_loc].as_ref()[haar_pos/2].unwrap_or(0)
             } else {
                 0
             }
@@ -97,6 +106,7 @@ impl ContextModeler {
         ];
 
         let sorted_lattice = wavelet_image.get_sorted_lattice();
+        let coefficients = wavelet_image.get_channel_coefficients(channel);
 
         let mut ind = 0;
         for level in (1..global_depth).rev() {
@@ -112,8 +122,8 @@ impl ContextModeler {
                         level,
                         parent_pos,
                         &wavelet_image.fractal_lattice,
+                        &coefficients,
                         &wavelet_image.global_position_map,
-                        channel,
                     );
                     if level == global_depth - 1 {
                         value_vectors[0][i] = value as f32;
@@ -148,26 +158,29 @@ impl ContextModeler {
         global_depth: u8,
         channel: usize,
     ) {
-        let mut width_predictors: Vec<[f32; 6]> = vec![];
-        for (matrix, residual_vector) in neighbourhood_matrices.iter().zip(residuals.iter()) {
-            let mut width_compounds = DMatrix::<f32>::zeros(matrix.nrows(), 6);
-            for (i, row) in matrix.row_iter().enumerate() {
-                let gradient_horizn = (row[0] - row[3]).abs();
-                let gradient_upper_horizn = (row[1] - row[2]).abs();
-                let gradient_down_horizn = (row[4] - row[5]).abs();
-                let gradient_vert_left = (row[1] - row[5]).abs();
-                let gradient_vert_right = (row[2] - row[4]).abs();
-                width_compounds[(i, 0)] = 1.0;
-                width_compounds[(i, 1)] = gradient_horizn;
-                width_compounds[(i, 2)] = gradient_upper_horizn;
-                width_compounds[(i, 3)] = gradient_down_horizn;
-                width_compounds[(i, 4)] = gradient_vert_left;
-                width_compounds[(i, 5)] = gradient_vert_right;
-            }
+        let width_predictors: Vec<[f32; 6]> = neighbourhood_matrices
+            .par_iter()
+            .zip(residuals.par_iter())
+            .map(|(matrix, residual_vector)| {
+                let mut width_compounds = DMatrix::<f32>::zeros(matrix.nrows(), 6);
+                for (i, row) in matrix.row_iter().enumerate() {
+                    let gradient_horizn = (row[0] - row[3]).abs();
+                    let gradient_upper_horizn = (row[1] - row[2]).abs();
+                    let gradient_down_horizn = (row[4] - row[5]).abs();
+                    let gradient_vert_left = (row[1] - row[5]).abs();
+                    let gradient_vert_right = (row[2] - row[4]).abs();
+                    width_compounds[(i, 0)] = 1.0;
+                    width_compounds[(i, 1)] = gradient_horizn;
+                    width_compounds[(i, 2)] = gradient_upper_horizn;
+                    width_compounds[(i, 3)] = gradient_down_horizn;
+                    width_compounds[(i, 4)] = gradient_vert_left;
+                    width_compounds[(i, 5)] = gradient_vert_right;
+                }
 
-            let least_squares_result = lstsq(&width_compounds, residual_vector, 1e-14).unwrap();
-            width_predictors.push(least_squares_result.solution.fixed_rows::<6>(0).into());
-        }
+                let least_squares_result = lstsq(&width_compounds, residual_vector, 1e-14).unwrap();
+                least_squares_result.solution.fixed_rows::<6>(0).into()
+            })
+            .collect();
 
         self.width_predictors[channel] = width_predictors;
     }
@@ -180,8 +193,8 @@ impl ContextModeler {
         channel: usize,
     ) -> Vec<DVector<f32>> {
         let results = neighbourhood_matrices
-            .iter()
-            .zip(values.iter())
+            .par_iter()
+            .zip(values.par_iter())
             .map(|(matrix, vector)| lstsq(matrix, vector, 1e-14).unwrap())
             .collect::<Vec<_>>();
 
@@ -1,60 +1,579 @@
+use std::io::Read;
 
-use crate::images::{CompressedImage, RasterImage};
-use crate::stages::wavelet_transform::WaveletImage;
-use crate::stages::{channel_transform, entropy_coding, quantization, wavelet_transform, serialize};
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::error::FriError;
+use crate::images::{
+    CompressedImage, EncodedFrame, Frame, ImageMetadata, RasterImage, Region, TiledImage,
+};
+use crate::stages::serialize::SerializedImage;
+use crate::stages::wavelet_transform::{WaveletImage, BASE_FRAC_DEPTH};
+use crate::stages::{
+    channel_transform, entropy_coding, quantization, serialize, temporal_prediction, wavelet_transform,
+};
 
 enum DecoderStage {
-    EncodedImage(Vec<u8>),
     EntropyDecoding(CompressedImage),
     Dequantization(WaveletImage),
     WaveletTransform(WaveletImage),
     ChannelTransform(RasterImage),
     RawImage(RasterImage),
-    Failure(String),
+    Failure(FriError),
 }
 
 impl DecoderStage {
-    fn forward(self) -> DecoderStage {
+    fn forward(self, decoder_options: &DecoderOpts) -> DecoderStage {
         match self {
-            DecoderStage::EncodedImage(data) => match serialize::decode(data) {
-                Ok(result) => DecoderStage::EntropyDecoding(result),
-                Err(reason) => DecoderStage::Failure(reason.to_string()),
-            }
-            DecoderStage::EntropyDecoding(data) => match entropy_coding::decode(data) {
+            DecoderStage::EntropyDecoding(data) => match entropy_coding::decode(data, decoder_options) {
                 Ok(result) => DecoderStage::Dequantization(result),
                 Err(reason) => DecoderStage::Failure(reason),
             },
             DecoderStage::Dequantization(data) => match quantization::decode(data) {
                 Ok(result) => DecoderStage::WaveletTransform(result),
-                Err(reason) => DecoderStage::Failure(reason),
-            },
-            DecoderStage::WaveletTransform(data) => match wavelet_transform::decode(data) {
-                Ok(result) => DecoderStage::ChannelTransform(result),
-                Err(reason) => DecoderStage::Failure(reason),
+                Err(reason) => DecoderStage::Failure(FriError::Stage { stage: "Dequantization", reason }),
             },
+            DecoderStage::WaveletTransform(data) => {
+                let result = match (&decoder_options.region, decoder_options.max_level) {
+                    (Some(region), Some(level)) => wavelet_transform::decode_preview_region(data, level, region),
+                    (Some(region), None) => wavelet_transform::decode_region(data, region),
+                    (None, Some(level)) => wavelet_transform::decode_preview(data, level),
+                    (None, None) => wavelet_transform::decode(data),
+                };
+                match result {
+                    Ok(result) => DecoderStage::ChannelTransform(result),
+                    Err(reason) => DecoderStage::Failure(FriError::Stage { stage: "Wavelet transform", reason }),
+                }
+            }
             DecoderStage::ChannelTransform(data) => match channel_transform::decode(data) {
                 Ok(result) => DecoderStage::RawImage(result),
-                Err(reason) => DecoderStage::Failure(reason),
+                Err(reason) => DecoderStage::Failure(FriError::Stage { stage: "Channel transform", reason }),
             },
             other => other,
         }
     }
 }
 
+// Default of DecoderOpts::max_pixels, decoding takes a few hundred bytes per pixel
+pub const DEFAULT_MAX_PIXELS: u64 = 1 << 24;
+
+#[derive(Clone, Copy)]
+pub struct DecoderOpts {
+    /// Last fractal level to decode, every two levels left out halve both sides
+    /// of the preview, whose pixels are the means of the blocks they cover
+    pub max_level: Option<u8>,
+    /// Rectangle to decode, only fractal groups intersecting it are entropy decoded
+    /// and the resulting image is cropped to it
+    pub region: Option<Region>,
+    /// Worker threads used by the decoder, zero picks the number of available cores
+    pub threads: usize,
+    /// Largest number of pixels an image may declare, streams exceeding it are
+    /// rejected before any memory is allocated for the image, None lifts the limit
+    pub max_pixels: Option<u64>,
+    /// Verify checksums of streams carrying them, skipping it speeds up decoding
+    pub verify_checksums: bool,
+}
+
+impl Default for DecoderOpts {
+    fn default() -> Self {
+        Self { max_level: None, region: None, threads: 0, max_pixels: Some(DEFAULT_MAX_PIXELS), verify_checksums: true }
+    }
+}
+
 pub struct FRIDecoder {
+    opts: DecoderOpts,
+    pool: ThreadPool,
 }
 
 impl FRIDecoder {
-    pub fn decode(self, data: Vec<u8>) -> Result<RasterImage, String> {
-        let mut stage = DecoderStage::EncodedImage(data);
+    pub fn new(opts: DecoderOpts) -> Result<FRIDecoder, FriError> {
+        let pool = ThreadPoolBuilder::new().num_threads(opts.threads).build()?;
+        Ok(FRIDecoder { opts, pool })
+    }
+
+    /*
+     * Channel streams, fractals and tiles are decoded concurrently inside the
+     * decoder pool, the result does not depend on the number of threads.
+     */
+    pub fn decode(self, data: Vec<u8>) -> Result<RasterImage, FriError> {
+        self.decode_from(data.as_slice())
+    }
+
+    /*
+     * Segments are pulled from the reader as they are parsed, the stream is never
+     * buffered as a whole.
+     */
+    pub fn decode_from<R: Read>(self, reader: R) -> Result<RasterImage, FriError> {
+        let image = serialize::decode_from(reader, self.opts.verify_checksums)?;
+        self.pool.install(|| Self::decode_serialized(image, &self.opts))
+    }
+
+    /*
+     * Frames of a sequence are decoded one at a time while iterating, other
+     * images are returned as a single frame.
+     */
+    pub fn decode_frames<R: Read>(self, reader: R) -> Result<FrameDecoder, FriError> {
+        let image = serialize::decode_from(reader, self.opts.verify_checksums)?;
+        let metadata = Self::get_metadata(&image).clone();
+        Self::check_limits(&metadata, &self.opts)?;
+
+        let (frames, single) = match image {
+            SerializedImage::Sequence(sequence) => (sequence.frames, None),
+            other => (vec![], Some(other)),
+        };
+        Ok(FrameDecoder {
+            decoder: self,
+            metadata,
+            frames: frames.into_iter(),
+            single,
+            state: SequenceState::default(),
+        })
+    }
+
+    fn get_metadata(image: &SerializedImage) -> &ImageMetadata {
+        match image {
+            SerializedImage::Single(compressed) => &compressed.metadata,
+            SerializedImage::Tiled(tiled) => &tiled.metadata,
+            SerializedImage::Sequence(sequence) => &sequence.metadata,
+        }
+    }
+
+    fn check_limits(metadata: &ImageMetadata, opts: &DecoderOpts) -> Result<(), FriError> {
+        if opts.max_level.is_some_and(|level| level >= BASE_FRAC_DEPTH) {
+            return Err(FriError::InvalidInput(format!("fractal level has to be below {}", BASE_FRAC_DEPTH)));
+        }
+        if opts.max_pixels.is_some_and(|limit| metadata.width as u64 * metadata.height as u64 > limit) {
+            return Err(FriError::DimensionLimit { width: metadata.width, height: metadata.height });
+        }
+        if let Some(region) = opts.region {
+            if !region.fits(metadata.width, metadata.height) {
+                return Err(FriError::InvalidRegion(region));
+            }
+        }
+        Ok(())
+    }
+
+    fn decode_serialized(image: SerializedImage, opts: &DecoderOpts) -> Result<RasterImage, FriError> {
+        Self::check_limits(Self::get_metadata(&image), opts)?;
+
+        match image {
+            SerializedImage::Single(compressed) => Self::run_stages(DecoderStage::EntropyDecoding(compressed), opts),
+            SerializedImage::Tiled(tiled) => Self::decode_tiles(tiled, opts),
+            SerializedImage::Sequence(sequence) => {
+                let frame = sequence.frames.into_iter().next().ok_or(FriError::MalformedSegment("FRM"))?;
+                Self::decode_frame(frame, &sequence.metadata, &mut SequenceState::default(), opts)
+                    .map(|frame| frame.image)
+            }
+        }
+    }
+
+    /*
+     * Predicted frames are restored from the quantized coefficients of the
+     * previous frame, which are kept until the next frame is decoded.
+     */
+    fn decode_frame(
+        frame: EncodedFrame,
+        metadata: &ImageMetadata,
+        state: &mut SequenceState,
+        opts: &DecoderOpts,
+    ) -> Result<Frame, FriError> {
+        let compressed = match serialize::decode(frame.data, opts.verify_checksums)? {
+            SerializedImage::Single(compressed)
+                if compressed.metadata.width == metadata.width
+                    && compressed.metadata.height == metadata.height
+                    && compressed.metadata.colorspace.num_channels() == metadata.colorspace.num_channels()
+                    && compressed.metadata.variant == metadata.variant =>
+            {
+                compressed
+            }
+            _ => return Err(FriError::MalformedSegment("FRM")),
+        };
+
+        let lattice = state
+            .lattice
+            .get_or_insert_with(|| WaveletImage::from_metadata(metadata.clone()))
+            .clone();
+        let mut decoded = entropy_coding::decode_with_lattice(lattice, compressed, opts)?;
+        if frame.predicted {
+            let previous = state.previous.as_ref().ok_or(FriError::MalformedSegment("FRM"))?;
+            decoded = temporal_prediction::decode(decoded, previous)
+                .map_err(|reason| FriError::Stage { stage: "Temporal prediction", reason })?;
+        }
+        state.previous = Some(decoded.clone());
+
+        let mut image = Self::run_stages(DecoderStage::Dequantization(decoded), opts)?;
+        image.metadata.embedded = metadata.embedded.clone();
+        Ok(Frame { image, duration_ms: frame.duration_ms })
+    }
+
+    fn run_stages(mut stage: DecoderStage, opts: &DecoderOpts) -> Result<RasterImage, FriError> {
         while !matches!(stage, DecoderStage::RawImage(_) | DecoderStage::Failure(_)) {
-            stage = stage.forward();
+            stage = stage.forward(opts);
         }
 
         match stage {
             DecoderStage::RawImage(result) => Ok(result),
-            DecoderStage::Failure(msg) => Err(String::from("Failed to decode: ".to_owned() + &msg)),
+            DecoderStage::Failure(reason) => Err(reason),
             _ => unreachable!(),
         }
     }
+
+    /*
+     * Decodes only the tiles intersecting the requested region, each of them
+     * restricted to its part of the region.
+     */
+    fn decode_tiles(image: TiledImage, opts: &DecoderOpts) -> Result<RasterImage, FriError> {
+        // Tile borders do not fall on the blocks averaged by a preview
+        if opts.max_level.is_some() {
+            return Err(FriError::Unsupported("previews of tiled images"));
+        }
+        let full = Region { x: 0, y: 0, width: image.metadata.width, height: image.metadata.height };
+        let target = opts.region.unwrap_or(full);
+        if !target.fits(full.width, full.height) {
+            return Err(FriError::InvalidRegion(target));
+        }
+
+        let mut metadata = image.metadata;
+        metadata.width = target.width;
+        metadata.height = target.height;
+        let mut raster = RasterImage {
+            data: vec![0; target.width as usize * target.height as usize * metadata.colorspace.bytes_per_pixel()],
+            metadata,
+        };
+
+        let decoded_tiles = image
+            .tiles
+            .into_par_iter()
+            .filter_map(|tile| tile.region.intersection(&target).map(|visible| (tile, visible)))
+            .map(|(tile, visible)| {
+                let tile_opts = DecoderOpts {
+                    region: Some(Region {
+                        x: visible.x - tile.region.x,
+                        y: visible.y - tile.region.y,
+                        width: visible.width,
+                        height: visible.height,
+                    }),
+                    ..*opts
+                };
+                serialize::decode(tile.data, opts.verify_checksums)
+                    .and_then(|image| Self::decode_serialized(image, &tile_opts))
+                    .map(|decoded| (decoded, visible))
+            })
+            .collect::<Result<Vec<_>, FriError>>()?;
+
+        for (decoded, visible) in decoded_tiles {
+            raster.paste(&decoded, visible.x - target.x, visible.y - target.y);
+        }
+
+        Ok(raster)
+    }
+
+    pub fn decode_region(mut self, data: Vec<u8>, region: Region) -> Result<RasterImage, FriError> {
+        self.opts.region = Some(region);
+        self.decode(data)
+    }
+}
+
+#[derive(Default)]
+struct SequenceState {
+    // Lattice with zero coefficients shared by every frame
+    lattice: Option<WaveletImage>,
+    // Quantized coefficients of the last decoded frame
+    previous: Option<WaveletImage>,
+}
+
+pub struct FrameDecoder {
+    decoder: FRIDecoder,
+    metadata: ImageMetadata,
+    frames: std::vec::IntoIter<EncodedFrame>,
+    single: Option<SerializedImage>,
+    state: SequenceState,
+}
+
+impl FrameDecoder {
+    /// Metadata of the stored image, dimensions of decoded frames follow the region
+    pub fn metadata(&self) -> &ImageMetadata {
+        &self.metadata
+    }
+}
+
+impl Iterator for FrameDecoder {
+    type Item = Result<Frame, FriError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let FRIDecoder { opts, pool } = &self.decoder;
+        if let Some(image) = self.single.take() {
+            let decoded = pool.install(|| FRIDecoder::decode_serialized(image, opts));
+            return Some(decoded.map(|image| Frame { image, duration_ms: 0 }));
+        }
+
+        let frame = self.frames.next()?;
+        let (metadata, state) = (&self.metadata, &mut self.state);
+        Some(pool.install(|| FRIDecoder::decode_frame(frame, metadata, state, opts)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encoder::{EncoderOpts, FRIEncoder};
+    use crate::images::ColorSpace;
+    use crate::metrics::psnr;
+    use crate::test_images::{self, gradient};
+
+    #[test]
+    fn thread_count_does_not_change_output() {
+        let (width, height) = (67, 45);
+        let data = test_images::noisy_gradient(width, height, 31);
+
+        // Tiles are decoded in parallel on top of channels and fractals
+        for tile_size in [None, Some(32)] {
+            let encoded = test_images::encode(EncoderOpts { tile_size, ..Default::default() }, width, height, &data);
+
+            for threads in [1, 4] {
+                let decoded = FRIDecoder::new(DecoderOpts { threads, ..Default::default() }).unwrap()
+                    .decode(encoded.clone())
+                    .unwrap();
+                assert_eq!(decoded.data, data);
+            }
+        }
+    }
+
+    fn box_filter(image: &RasterImage, scale: u32) -> RasterImage {
+        let (width, height) = (image.metadata.width.div_ceil(scale), image.metadata.height.div_ceil(scale));
+        let mut data = vec![];
+        for y in 0..height {
+            for x in 0..width {
+                for channel in 0..3 {
+                    let block: Vec<i32> = (y * scale..((y + 1) * scale).min(image.metadata.height))
+                        .flat_map(|py| (x * scale..((x + 1) * scale).min(image.metadata.width)).map(move |px| (px, py)))
+                        .map(|(px, py)| image.get_pixel(px as i32, py as i32, channel).unwrap())
+                        .collect();
+                    data.push((block.iter().sum::<i32>() as f32 / block.len() as f32).round() as u8);
+                }
+            }
+        }
+        test_images::raster(width, height, data)
+    }
+
+    #[test]
+    fn previews_are_downscaled_block_means() {
+        let (width, height) = (101, 77);
+        let original = test_images::raster(width, height, test_images::noisy_gradient(width, height, 12));
+        let encoded = test_images::encode(EncoderOpts::default(), width, height, &original.data);
+        let preview = |max_level, region| {
+            FRIDecoder::new(DecoderOpts { max_level: Some(max_level), region, ..Default::default() }).unwrap()
+                .decode(encoded.clone())
+                .unwrap()
+        };
+
+        // Every level decoded leaves nothing to average, levels past the lattice do not exist
+        assert_eq!(preview(BASE_FRAC_DEPTH - 1, None).data, original.data);
+        for max_level in [BASE_FRAC_DEPTH, u8::MAX] {
+            assert!(matches!(
+                FRIDecoder::new(DecoderOpts { max_level: Some(max_level), ..Default::default() }).unwrap()
+                    .decode(encoded.clone()),
+                Err(FriError::InvalidInput(_))
+            ));
+        }
+
+        // Subtrees are twindragons rather than squares, so the means only match the
+        // box filter up to the pixels each shape trades with its neighbours
+        for (max_level, scale) in [(6, 2), (5, 2), (4, 4), (2, 8)] {
+            let decoded = preview(max_level, None);
+            let reference = box_filter(&original, scale);
+            assert_eq!(
+                (decoded.metadata.width, decoded.metadata.height),
+                (width.div_ceil(scale), height.div_ceil(scale))
+            );
+            let quality = psnr(&reference, &decoded);
+            assert!(quality > 28., "preview at level {} is {} dB from the box filter", max_level, quality);
+        }
+
+        // Regions keep every block they touch
+        let region = Region { x: 13, y: 30, width: 50, height: 21 };
+        let decoded = preview(4, Some(region));
+        let full = preview(4, None);
+        let scaled = Region { x: 3, y: 7, width: 13, height: 6 };
+        assert_eq!(decoded.data, full.crop(&scaled).unwrap().data);
+    }
+
+    #[test]
+    fn regions_match_crop_of_full_decode() {
+        // Groups cover 256 pixel cells of fractal centers, the second region spans
+        // several of them, the tiled image cuts the last one into four tiles
+        let cases = [
+            (272, 264, None, vec![
+                Region { x: 17, y: 9, width: 31, height: 26 },
+                Region { x: 236, y: 230, width: 31, height: 23 },
+            ]),
+            (131, 97, Some(64), vec![Region { x: 50, y: 41, width: 37, height: 33 }]),
+        ];
+
+        for (width, height, tile_size, regions) in cases {
+            let data = test_images::noisy_gradient(width, height, 12);
+            let encoded = test_images::encode(EncoderOpts { tile_size, ..Default::default() }, width, height, &data);
+            let full = test_images::decode(encoded.clone());
+            assert_eq!(full.data, data);
+
+            for region in regions {
+                let decoded = FRIDecoder::new(DecoderOpts::default()).unwrap()
+                    .decode_region(encoded.clone(), region)
+                    .unwrap();
+                assert_eq!((decoded.metadata.width, decoded.metadata.height), (region.width, region.height));
+                assert_eq!(decoded.data, full.crop(&region).unwrap().data, "{:?} of tiles {:?}", region, tile_size);
+            }
+        }
+    }
+
+    #[test]
+    fn streaming_round_trip() {
+        let (width, height) = (43, 31);
+        let data = test_images::noisy_gradient(width, height, 31);
+        let mut encoded = vec![];
+        FRIEncoder::new(EncoderOpts::default()).unwrap()
+            .encode_to(data.clone(), height, width, ColorSpace::RGB, &mut encoded)
+            .unwrap();
+
+        let decoded = FRIDecoder::new(DecoderOpts::default()).unwrap()
+            .decode_from(std::io::Cursor::new(&encoded))
+            .unwrap();
+        assert_eq!(decoded.data, data);
+
+        let truncated = &encoded[..encoded.len() / 2];
+        assert!(matches!(
+            FRIDecoder::new(DecoderOpts::default()).unwrap().decode_from(truncated),
+            Err(FriError::TruncatedSegment(_))
+        ));
+    }
+
+    #[test]
+    fn errors_report_their_cause() {
+        let (width, height) = (40, 30);
+        let data = gradient(width, height);
+        let encoded = test_images::encode(EncoderOpts::default(), width, height, &data);
+
+        let mut corrupted = encoded.clone();
+        corrupted[0] = b'x';
+        assert!(matches!(
+            FRIDecoder::new(DecoderOpts::default()).unwrap().decode(corrupted),
+            Err(FriError::InvalidHeader(_))
+        ));
+
+        let region = Region { x: 30, y: 0, width: 20, height: 10 };
+        assert!(matches!(
+            FRIDecoder::new(DecoderOpts::default()).unwrap().decode_region(encoded, region),
+            Err(FriError::InvalidRegion(_))
+        ));
+
+        assert!(matches!(
+            FRIEncoder::new(EncoderOpts::default()).unwrap().encode(data, height + 1, width, ColorSpace::RGB),
+            Err(FriError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn corrupted_streams_do_not_panic() {
+        let (width, height) = (24, 20);
+        let encoded = test_images::encode(EncoderOpts::default(), width, height, &gradient(width, height));
+        // Checksums would reject most of the streams before they reach the decoder
+        let opts = || DecoderOpts {
+            threads: 1,
+            max_pixels: Some(1 << 16),
+            verify_checksums: false,
+            ..Default::default()
+        };
+
+        for length in (0..encoded.len()).step_by(encoded.len() / 16) {
+            let _ = FRIDecoder::new(opts()).unwrap().decode(encoded[..length].to_vec());
+        }
+        for position in (0..encoded.len()).step_by(encoded.len() / 32) {
+            let mut corrupted = encoded.clone();
+            corrupted[position] ^= 0x55;
+            let _ = FRIDecoder::new(opts()).unwrap().decode(corrupted);
+        }
+
+        let mut huge = encoded.clone();
+        huge[4..12].copy_from_slice(&[0, 0, 1, 0, 0, 0, 1, 0]);
+        assert!(matches!(
+            FRIDecoder::new(opts()).unwrap().decode(huge),
+            Err(FriError::DimensionLimit { .. })
+        ));
+    }
+
+    #[test]
+    fn truncated_and_oversized_headers_are_rejected() {
+        use crate::images::FractalVariant;
+        use crate::stages::serialize::SegmentWriter;
+
+        let header_only = |width, height| {
+            let metadata = ImageMetadata {
+                height,
+                width,
+                colorspace: ColorSpace::RGB,
+                variant: FractalVariant::TameTwindragon,
+                embedded: Default::default(),
+            };
+            let mut bytes = vec![];
+            let mut writer = SegmentWriter::new(&mut bytes, false);
+            serialize::encode_header(&mut writer, &metadata).unwrap();
+            serialize::encode_end(&mut writer).unwrap();
+            drop(writer);
+            bytes
+        };
+
+        // Every channel is missing, which used to decode to a black image
+        assert!(matches!(
+            FRIDecoder::new(DecoderOpts::default()).unwrap().decode(header_only(24, 20)),
+            Err(FriError::MalformedSegment("EOC"))
+        ));
+
+        // The default limit rejects the header before the lattice is allocated
+        let huge = header_only(8000, 8000);
+        assert!(huge.len() < 20);
+        assert!(matches!(
+            FRIDecoder::new(DecoderOpts::default()).unwrap().decode(huge),
+            Err(FriError::DimensionLimit { width: 8000, height: 8000 })
+        ));
+    }
+
+    /*
+     * Inputs that made the decoder panic before, kept next to the fuzz target
+     * in fuzz/regressions.
+     */
+    #[test]
+    fn fuzz_regressions_do_not_panic() {
+        let decode = |data: &[u8]| FRIDecoder::new(DecoderOpts::default()).unwrap().decode(data.to_vec());
+
+        // Context widths of 1e-30 saturated the Laplace tables built from them
+        for data in [
+            &include_bytes!("../fuzz/regressions/ctx_tiny_width.fri")[..],
+            &include_bytes!("../fuzz/regressions/ctx_tiny_width_adaptive.fri")[..],
+        ] {
+            assert!(matches!(decode(data), Err(FriError::MalformedSegment("CTX"))));
+        }
+
+        // Single channel images used to end up with an empty fractal lattice
+        let luma = decode(include_bytes!("../fuzz/regressions/luma_header.fri")).unwrap();
+        assert_eq!(luma.data.len(), 24 * 20);
+    }
+
+    #[test]
+    fn luma_round_trip() {
+        use crate::encoder::EncoderQuality;
+        use crate::test_images::noisy_gradient;
+
+        let (width, height) = (37, 23);
+        let data: Vec<u8> = noisy_gradient(width, height, 31).into_iter().step_by(3).collect();
+        for (quality, tile_size) in [(EncoderQuality::Lossless, None), (EncoderQuality::Low, Some(16))] {
+            let encoded = FRIEncoder::new(EncoderOpts { quality, tile_size, ..Default::default() }).unwrap()
+                .encode(data.clone(), height, width, ColorSpace::Luma)
+                .unwrap();
+            let decoded = test_images::decode(encoded);
+            assert_eq!(decoded.data.len(), data.len());
+            if let EncoderQuality::Lossless = quality {
+                assert_eq!(decoded.data, data);
+            }
+        }
+    }
 }
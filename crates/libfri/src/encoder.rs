@@ -1,7 +1,20 @@
-use crate::images::{FractalVariant, ColorSpace, CompressedImage, RasterImage, ImageMetadata};
+use std::io::Write;
+
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::error::{check_dimensions, FriError};
+use crate::metrics;
+use crate::images::{
+    FractalVariant, ColorSpace, CompressedImage, EmbeddedMetadata, EncodedFrame, EncodedTile, EntropyCoder, Frame,
+    RasterImage, ImageMetadata, Region,
+};
 use crate::stages::entropy_coding::AnsContext;
+use crate::stages::serialize::SegmentWriter;
 use crate::stages::wavelet_transform::WaveletImage;
-use crate::stages::{channel_transform, entropy_coding, prediction, quantization, serialize, wavelet_transform};
+use crate::stages::{
+    channel_transform, entropy_coding, prediction, quantization, serialize, temporal_prediction,
+    wavelet_transform,
+};
 
 enum EncoderStage {
     RawImage(RasterImage),
@@ -12,7 +25,7 @@ enum EncoderStage {
     EntropyEncoding(WaveletImage, [Vec<AnsContext>; 3]),
     EncodedImage(CompressedImage),
     SerializedImage(Vec<u8>),
-    Failure(String),
+    Failure(FriError),
 }
 
 impl EncoderStage {
@@ -21,33 +34,102 @@ impl EncoderStage {
             EncoderStage::RawImage(data) => EncoderStage::ChannelTransform(data),
             EncoderStage::ChannelTransform(data) => match channel_transform::encode(data) {
                 Ok(result) => EncoderStage::WaveletTransform(result),
-                Err(reason) => EncoderStage::Failure(reason),
+                Err(reason) => EncoderStage::Failure(FriError::Stage { stage: "Channel transform", reason }),
             },
             EncoderStage::WaveletTransform(data) => match wavelet_transform::encode(data, encoder_options) {
                 Ok(result) => EncoderStage::Quantization(result),
-                Err(reason) => EncoderStage::Failure(reason),
+                Err(reason) => EncoderStage::Failure(FriError::Stage { stage: "Wavelet transform", reason }),
             },
-            EncoderStage::Quantization(data) => match quantization::encode(data) {
+            EncoderStage::Quantization(data) => match quantization::encode(data, encoder_options) {
                 Ok(result) => EncoderStage::Prediction(result),
-                Err(reason) => EncoderStage::Failure(reason),
+                Err(reason) => EncoderStage::Failure(FriError::Stage { stage: "Quantization", reason }),
             },
             EncoderStage::Prediction(mut data) => match prediction::encode(&mut data, encoder_options) {
                 Ok(result) => EncoderStage::EntropyEncoding(data, result),
-                Err(reason) => EncoderStage::Failure(reason),
+                Err(reason) => EncoderStage::Failure(FriError::Stage { stage: "Prediction", reason }),
             },
             EncoderStage::EntropyEncoding(data, contexts) => match entropy_coding::encode(data, contexts, encoder_options) {
                 Ok(result) => EncoderStage::EncodedImage(result),
-                Err(reason) => EncoderStage::Failure(reason),
+                Err(reason) => EncoderStage::Failure(FriError::Stage { stage: "Entropy coding", reason }),
             },
-            EncoderStage::EncodedImage(data) => match serialize::encode(data) {
+            EncoderStage::EncodedImage(data) => match serialize::encode(data, encoder_options.checksums) {
                 Ok(result) => EncoderStage::SerializedImage(result),
-                Err(reason) => EncoderStage::Failure(reason.to_string())
+                Err(reason) => EncoderStage::Failure(reason),
             }
             other => other,
         }
     }
+
+    fn run_until(
+        mut self,
+        encoder_options: &mut EncoderOpts,
+        is_done: fn(&EncoderStage) -> bool,
+    ) -> EncoderStage {
+        while !is_done(&self) && !matches!(self, EncoderStage::Failure(_)) {
+            self = self.forward(encoder_options);
+        }
+        self
+    }
+}
+
+/*
+ * Splits length into the smallest number of spans not longer than tile_size.
+ * All spans have nearly equal lengths, so no thin slivers are left at the edges.
+ */
+fn get_tile_spans(length: u32, tile_size: u32) -> Vec<(u32, u32)> {
+    let count = length.div_ceil(tile_size) as u64;
+    (0..count)
+        .map(|i| {
+            let start = (length as u64 * i / count) as u32;
+            let end = (length as u64 * (i + 1) / count) as u32;
+            (start, end - start)
+        })
+        .collect()
+}
+
+const MAX_QUANTIZATION_SCALE: f32 = 16.;
+const RATE_CONTROL_ITERATIONS: usize = 10;
+
+pub enum RateTarget {
+    Bytes(usize),
+    BitsPerPixel(f32),
 }
 
+impl RateTarget {
+    fn get_byte_budget(&self, height: u32, width: u32) -> usize {
+        match self {
+            RateTarget::Bytes(bytes) => *bytes,
+            RateTarget::BitsPerPixel(bpp) => (*bpp as f64 * height as f64 * width as f64 / 8.) as usize,
+        }
+    }
+}
+
+pub enum QualityTarget {
+    Psnr(f32),
+    Ssim(f32),
+}
+
+impl QualityTarget {
+    fn measure(&self, reference: &RasterImage, distorted: &RasterImage) -> f32 {
+        match self {
+            QualityTarget::Psnr(_) => metrics::psnr(reference, distorted),
+            QualityTarget::Ssim(_) => metrics::ssim(reference, distorted),
+        }
+    }
+
+    fn is_met(&self, achieved: f32) -> bool {
+        match self {
+            QualityTarget::Psnr(target) | QualityTarget::Ssim(target) => achieved >= *target,
+        }
+    }
+}
+
+pub struct QualityEncoding {
+    pub data: Vec<u8>,
+    pub achieved: f32,
+}
+
+#[derive(Clone, Copy)]
 pub enum EncoderQuality {
     Low,
     Medium,
@@ -57,6 +139,24 @@ pub enum EncoderQuality {
 
 pub struct EncoderOpts {
    pub quality: EncoderQuality,
+   pub variant: FractalVariant,
+   pub quantization_scale: f32,
+   pub tile_size: Option<u32>,
+   // Worker threads used by the encoder, zero picks the number of available cores
+   pub threads: usize,
+   // Follow every segment with its CRC32, so corruption is detected when decoding
+   pub checksums: bool,
+   // Adapt rANS context probabilities to the coded symbols instead of storing tables
+   pub adaptive: bool,
+   // Number of contexts residuals are split into, chosen per channel when unset
+   pub contexts: Option<usize>,
+   // Backend coding the residuals, rANS is faster and the binary coder smaller
+   pub entropy_coder: EntropyCoder,
+   // ICC profile, EXIF and XMP stored along with the image
+   pub embedded_metadata: EmbeddedMetadata,
+   // Every this many frames a sequence frame is coded without temporal prediction,
+   // zero codes only the first frame on its own
+   pub keyframe_interval: usize,
    pub emit_coefficients: bool,
    pub value_prediction_params: [Vec<[f32; 6]>; 4],
    pub width_prediction_params: [Vec<[f32; 6]>; 4],
@@ -65,6 +165,7 @@ pub struct EncoderOpts {
 
 pub struct FRIEncoder {
     opts: EncoderOpts,
+    pool: ThreadPool,
 }
 
 impl Default for EncoderOpts {
@@ -72,6 +173,16 @@ impl Default for EncoderOpts {
         Self {
             emit_coefficients: false,
             quality: EncoderQuality::Lossless,
+            variant: FractalVariant::TameTwindragon,
+            quantization_scale: 1.,
+            tile_size: None,
+            threads: 0,
+            checksums: true,
+            adaptive: false,
+            contexts: None,
+            entropy_coder: EntropyCoder::Rans,
+            embedded_metadata: EmbeddedMetadata::default(),
+            keyframe_interval: 0,
             value_prediction_params: Default::default(),
             width_prediction_params: Default::default(),
             verbose: false,
@@ -80,31 +191,447 @@ impl Default for EncoderOpts {
 }
 
 impl FRIEncoder {
-    pub fn new(opts: EncoderOpts) -> FRIEncoder {
-        FRIEncoder{ opts }
+    pub fn new(opts: EncoderOpts) -> Result<FRIEncoder, FriError> {
+        // Adaptive contexts replace the stored rANS tables, the binary coder adapts on its own
+        if opts.adaptive && opts.entropy_coder == EntropyCoder::Binary {
+            return Err(FriError::Unsupported("adaptive contexts with the binary coder"));
+        }
+        let pool = ThreadPoolBuilder::new().num_threads(opts.threads).build()?;
+        Ok(FRIEncoder { opts, pool })
+    }
+
+    /*
+     * Stages are run inside the encoder pool, so every parallel iterator they use
+     * is bounded by the configured thread count.
+     */
+    fn run_to_serialized(&mut self, stage: EncoderStage) -> Result<Vec<u8>, FriError> {
+        let opts = &mut self.opts;
+        match self.pool.install(|| stage.run_until(opts, |s| matches!(s, EncoderStage::SerializedImage(_)))) {
+            EncoderStage::SerializedImage(image) => Ok(image),
+            EncoderStage::Failure(reason) => Err(reason),
+            _ => unreachable!(),
+        }
+    }
+
+    fn get_raster(
+        &self,
+        data: Vec<u8>,
+        height: u32,
+        width: u32,
+        colorspace: ColorSpace,
+    ) -> Result<RasterImage, FriError> {
+        check_dimensions(width, height)?;
+        let expected = width as usize * height as usize * colorspace.num_channels();
+        if data.len() != expected {
+            return Err(FriError::InvalidInput(format!(
+                "expected {} bytes of pixel data, got {}",
+                expected,
+                data.len()
+            )));
+        }
+
+        Ok(RasterImage {
+            data,
+            metadata: ImageMetadata {
+                height,
+                width,
+                colorspace,
+                variant: self.opts.variant,
+                embedded: self.opts.embedded_metadata.clone(),
+            }
+        })
+    }
+
+    fn run_to_compressed(&mut self, stage: EncoderStage) -> Result<CompressedImage, FriError> {
+        let opts = &mut self.opts;
+        match self.pool.install(|| stage.run_until(opts, |s| matches!(s, EncoderStage::EncodedImage(_)))) {
+            EncoderStage::EncodedImage(image) => Ok(image),
+            EncoderStage::Failure(reason) => Err(reason),
+            _ => unreachable!(),
+        }
+    }
+
+    fn decompose(&mut self, image: RasterImage) -> Result<WaveletImage, FriError> {
+        let opts = &mut self.opts;
+        match self.pool.install(|| {
+            EncoderStage::RawImage(image).run_until(opts, |s| matches!(s, EncoderStage::Quantization(_)))
+        }) {
+            EncoderStage::Quantization(wavelet_image) => Ok(wavelet_image),
+            EncoderStage::Failure(reason) => Err(reason),
+            _ => unreachable!(),
+        }
     }
 
     pub fn encode(
+        self,
+        data: Vec<u8>,
+        height: u32,
+        width: u32,
+        colorspace: ColorSpace,
+    ) -> Result<Vec<u8>, FriError> {
+        let mut serial = Vec::new();
+        self.encode_to(data, height, width, colorspace, &mut serial)?;
+        Ok(serial)
+    }
+
+    /*
+     * Writes the encoded image straight into the writer, tiles are written as soon
+     * as each of them is encoded.
+     */
+    pub fn encode_to<W: Write>(
         mut self,
         data: Vec<u8>,
         height: u32,
         width: u32,
         colorspace: ColorSpace,
-    ) -> Result<Vec<u8>, String> {
-        let image = RasterImage {
-            data,
-            metadata: ImageMetadata{height, width, colorspace, variant: FractalVariant::TameTwindragon}
+        writer: W,
+    ) -> Result<(), FriError> {
+        let image = self.get_raster(data, height, width, colorspace)?;
+
+        match self.opts.tile_size {
+            Some(tile_size) => self.encode_tiled(image, tile_size, writer),
+            None => {
+                let compressed = self.run_to_compressed(EncoderStage::RawImage(image))?;
+                serialize::encode_to(compressed, &mut SegmentWriter::new(writer, self.opts.checksums))
+            }
+        }
+    }
+
+    /*
+     * Every tile runs through the whole pipeline on its own, so only the lattice of
+     * a single tile is kept in memory at once and tiles can be decoded independently.
+     */
+    fn encode_tiled<W: Write>(&mut self, image: RasterImage, tile_size: u32, writer: W) -> Result<(), FriError> {
+        if tile_size == 0 {
+            return Err(FriError::InvalidInput(String::from("tile size has to be positive")));
+        }
+
+        let mut writer = SegmentWriter::new(writer, self.opts.checksums);
+        serialize::encode_header(&mut writer, &image.metadata)?;
+        for (y, height) in get_tile_spans(image.metadata.height, tile_size) {
+            for (x, width) in get_tile_spans(image.metadata.width, tile_size) {
+                let region = Region { x, y, width, height };
+                // Embedded metadata is stored once in the header of the whole image
+                let mut tile = image.crop(&region)?;
+                tile.metadata.embedded = EmbeddedMetadata::default();
+                let data = self.run_to_serialized(EncoderStage::RawImage(tile))?;
+                serialize::encode_tile(&EncodedTile { region, data }, &mut writer)?;
+            }
+        }
+        serialize::encode_end(&mut writer)
+    }
+
+    /*
+     * Encodes frames of equal dimensions into a sequence, every frame is written
+     * as soon as it is encoded. The first frame decides the dimensions and
+     * colorspace, the following ones code their quantized coefficients as
+     * differences to the previous frame except for keyframes.
+     */
+    pub fn encode_sequence<I, W>(mut self, frames: I, writer: W) -> Result<(), FriError>
+    where
+        I: IntoIterator<Item = Frame>,
+        W: Write,
+    {
+        if self.opts.tile_size.is_some() {
+            return Err(FriError::Unsupported("tiled encoding of sequences"));
+        }
+
+        let mut frames = frames.into_iter().peekable();
+        let ImageMetadata { height, width, colorspace, .. } = match frames.peek() {
+            Some(frame) => frame.image.metadata.clone(),
+            None => return Err(FriError::InvalidInput(String::from("sequence has no frames"))),
         };
 
-        let mut stage = EncoderStage::RawImage(image);
-        while !matches!(stage, EncoderStage::SerializedImage(_) | EncoderStage::Failure(_)) {
-            stage = stage.forward(&mut self.opts);
+        let mut writer = SegmentWriter::new(writer, self.opts.checksums);
+        let mut lattice: Option<WaveletImage> = None;
+        let mut previous: Option<WaveletImage> = None;
+        for (index, Frame { image, duration_ms }) in frames.enumerate() {
+            if image.metadata.width != width || image.metadata.height != height {
+                return Err(FriError::InvalidInput(format!(
+                    "frame {} is {}x{}, the sequence is {}x{}",
+                    index, image.metadata.width, image.metadata.height, width, height
+                )));
+            }
+            let mut image = self.get_raster(image.data, height, width, colorspace.clone())?;
+            if index == 0 {
+                serialize::encode_header(&mut writer, &image.metadata)?;
+            }
+            // Embedded metadata is stored once in the header of the sequence
+            image.metadata.embedded = EmbeddedMetadata::default();
+
+            let interval = self.opts.keyframe_interval;
+            let keyframe = index == 0 || (interval > 0 && index % interval == 0);
+            let reference = if keyframe { None } else { previous.as_ref() };
+            let (data, quantized) = self.encode_frame(image, &mut lattice, reference)?;
+            previous = Some(quantized);
+
+            let frame = EncodedFrame { duration_ms, predicted: !keyframe, data };
+            serialize::encode_frame(&frame, &mut writer)?;
         }
+        serialize::encode_end(&mut writer)
+    }
 
-        match stage {
-            EncoderStage::SerializedImage(image) => Ok(image),
-            EncoderStage::Failure(msg) => Err(String::from("Failed to decode: ".to_owned() + &msg)),
-            _ => unreachable!(),
+    /*
+     * Frames after the first one reuse its lattice. Returns the serialized frame
+     * and its quantized coefficients, the reference for the next frame.
+     */
+    fn encode_frame(
+        &mut self,
+        image: RasterImage,
+        lattice: &mut Option<WaveletImage>,
+        reference: Option<&WaveletImage>,
+    ) -> Result<(Vec<u8>, WaveletImage), FriError> {
+        let wavelet_image = match lattice {
+            None => {
+                let wavelet_image = self.decompose(image)?;
+                *lattice = Some(wavelet_image.clone());
+                wavelet_image
+            }
+            Some(lattice) => {
+                let transformed = channel_transform::encode(image)
+                    .map_err(|reason| FriError::Stage { stage: "Channel transform", reason })?;
+                self.pool.install(|| lattice.with_raster(transformed))
+            }
+        };
+
+        let opts = &self.opts;
+        let quantized = self
+            .pool
+            .install(|| quantization::encode(wavelet_image, opts))
+            .map_err(|reason| FriError::Stage { stage: "Quantization", reason })?;
+        let residual = match reference {
+            Some(reference) => self
+                .pool
+                .install(|| temporal_prediction::encode(quantized.clone(), reference))
+                .map_err(|reason| FriError::Stage { stage: "Temporal prediction", reason })?,
+            None => quantized.clone(),
+        };
+
+        let data = self.run_to_serialized(EncoderStage::Prediction(residual))?;
+        Ok((data, quantized))
+    }
+
+    /*
+     * Encodes the image so that the serialized output fits into the given budget.
+     * Bisection runs over the scale of the quantization matrix selected by `quality`,
+     * lossless quality falls back to the medium matrix as it has no shape to scale.
+     * The wavelet decomposition is computed once and reused by every iteration.
+     */
+    pub fn encode_to_rate(
+        mut self,
+        data: Vec<u8>,
+        height: u32,
+        width: u32,
+        colorspace: ColorSpace,
+        target: RateTarget,
+    ) -> Result<Vec<u8>, FriError> {
+        let budget = target.get_byte_budget(height, width);
+        let image = self.get_raster(data, height, width, colorspace)?;
+
+        if self.opts.tile_size.is_some() {
+            return Err(FriError::Unsupported("size and quality targets with tiled encoding"));
+        }
+        let wavelet_image = self.decompose(image)?;
+        if let EncoderQuality::Lossless = self.opts.quality {
+            self.opts.quality = EncoderQuality::Medium;
+        }
+
+        let mut encode_with_scale = |scale: f32| -> Result<Vec<u8>, FriError> {
+            self.opts.quantization_scale = scale;
+            self.run_to_serialized(EncoderStage::Quantization(wavelet_image.clone()))
+        };
+
+        // Scale of zero leaves every step at one, which makes the coding lossless
+        let lossless = encode_with_scale(0.)?;
+        if lossless.len() <= budget {
+            return Ok(lossless);
+        }
+
+        let mut best = encode_with_scale(MAX_QUANTIZATION_SCALE)?;
+        if best.len() > budget {
+            return Err(FriError::Stage {
+                stage: "Rate control",
+                reason: format!(
+                    "cannot fit the image into {} bytes, smallest achievable size is {} bytes",
+                    budget,
+                    best.len()
+                ),
+            });
+        }
+
+        let (mut low, mut high) = (0., MAX_QUANTIZATION_SCALE);
+        for _ in 0..RATE_CONTROL_ITERATIONS {
+            let scale = (low + high) / 2.;
+            let result = encode_with_scale(scale)?;
+            if result.len() <= budget {
+                high = scale;
+                best = result;
+            } else {
+                low = scale;
+            }
+        }
+
+        Ok(best)
+    }
+
+    /*
+     * Encodes the image with the coarsest quantization that still reaches the target
+     * quality. Every candidate is reconstructed from its quantized coefficients with
+     * the decoder stages and measured against the input image.
+     */
+    pub fn encode_to_quality(
+        mut self,
+        data: Vec<u8>,
+        height: u32,
+        width: u32,
+        colorspace: ColorSpace,
+        target: QualityTarget,
+    ) -> Result<QualityEncoding, FriError> {
+        let image = self.get_raster(data, height, width, colorspace)?;
+
+        if self.opts.tile_size.is_some() {
+            return Err(FriError::Unsupported("size and quality targets with tiled encoding"));
+        }
+        let wavelet_image = self.decompose(image.clone())?;
+        if let EncoderQuality::Lossless = self.opts.quality {
+            self.opts.quality = EncoderQuality::Medium;
+        }
+
+        let mut encode_with_scale = |scale: f32| -> Result<Option<QualityEncoding>, FriError> {
+            self.opts.quantization_scale = scale;
+            let opts = &self.opts;
+            let (quantized, reconstructed) = self
+                .pool
+                .install(|| -> Result<_, String> {
+                    let quantized = quantization::encode(wavelet_image.clone(), opts)?;
+                    let reconstructed = quantization::decode(quantized.clone())
+                        .and_then(wavelet_transform::decode)
+                        .and_then(channel_transform::decode)?;
+                    Ok((quantized, reconstructed))
+                })
+                .map_err(|reason| FriError::Stage { stage: "Quality control", reason })?;
+
+            let achieved = target.measure(&image, &reconstructed);
+            if !target.is_met(achieved) {
+                return Ok(None);
+            }
+            let data = self.run_to_serialized(EncoderStage::Prediction(quantized))?;
+            Ok(Some(QualityEncoding { data, achieved }))
+        };
+
+        if let Some(result) = encode_with_scale(MAX_QUANTIZATION_SCALE)? {
+            return Ok(result);
+        }
+
+        // Scale of zero leaves every step at one, so the target is always met
+        let mut best = encode_with_scale(0.)?
+            .ok_or_else(|| FriError::Stage {
+                stage: "Quality control",
+                reason: String::from("lossless coding missed the quality target"),
+            })?;
+
+        let (mut low, mut high) = (0., MAX_QUANTIZATION_SCALE);
+        for _ in 0..RATE_CONTROL_ITERATIONS {
+            let scale = (low + high) / 2.;
+            match encode_with_scale(scale)? {
+                Some(result) => {
+                    low = scale;
+                    if result.data.len() <= best.data.len() {
+                        best = result;
+                    }
+                }
+                None => high = scale,
+            }
+        }
+
+        Ok(best)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_images::{self, noisy_gradient};
+
+    #[test]
+    fn thread_count_does_not_change_output() {
+        let (width, height) = (67, 45);
+        let data = noisy_gradient(width, height, 31);
+
+        let encode = |threads| test_images::encode(EncoderOpts { threads, ..Default::default() }, width, height, &data);
+        assert_eq!(encode(1), encode(4));
+    }
+
+    #[test]
+    fn tiled_lossless_round_trip() {
+        use crate::test_images::saturated;
+
+        // Neither side is a multiple of the tile size, so tiles of the same row or
+        // column differ in size
+        let (width, height) = (101, 70);
+        let opts = || EncoderOpts { tile_size: Some(32), ..Default::default() };
+        for data in [noisy_gradient(width, height, 12), saturated(width, height)] {
+            let decoded = test_images::round_trip(opts(), width, height, &data);
+            assert_eq!((decoded.metadata.width, decoded.metadata.height), (width, height));
+            assert_eq!(decoded.data, data);
+        }
+        assert_eq!(get_tile_spans(101, 32), vec![(0, 25), (25, 25), (50, 25), (75, 26)]);
+    }
+
+    #[test]
+    fn rate_control_stays_within_budget() {
+        let (width, height) = (37, 23);
+        let data = noisy_gradient(width, height, 63);
+        let encode = |target| {
+            FRIEncoder::new(EncoderOpts::default())
+                .unwrap()
+                .encode_to_rate(data.clone(), height, width, ColorSpace::RGB, target)
+        };
+        let lossless = test_images::encode(EncoderOpts::default(), width, height, &data);
+
+        for target in [RateTarget::Bytes(lossless.len() / 2), RateTarget::BitsPerPixel(12.)] {
+            let budget = target.get_byte_budget(height, width);
+            let encoded = encode(target).unwrap();
+            assert!(encoded.len() <= budget, "{} > {}", encoded.len(), budget);
+            assert_eq!(test_images::decode(encoded).data.len(), data.len());
+        }
+        assert_eq!(encode(RateTarget::Bytes(lossless.len())).unwrap(), lossless);
+        assert!(matches!(encode(RateTarget::Bytes(16)), Err(FriError::Stage { .. })));
+
+        // Pixel count of the largest images does not fit into u32
+        assert_eq!(RateTarget::BitsPerPixel(1.).get_byte_budget(1 << 16, 1 << 16), 1 << 29);
+    }
+
+    #[test]
+    fn adaptive_binary_coder_is_rejected() {
+        let opts = EncoderOpts { adaptive: true, entropy_coder: EntropyCoder::Binary, ..Default::default() };
+        assert!(matches!(FRIEncoder::new(opts), Err(FriError::Unsupported(_))));
+    }
+
+    #[test]
+    fn quality_target_is_met_after_decoding() {
+        use crate::test_images::{raster, saturated};
+
+        let (width, height) = (37, 23);
+        for data in [noisy_gradient(width, height, 63), saturated(width, height)] {
+            let reference = raster(width, height, data.clone());
+            let lossless = test_images::encode(EncoderOpts::default(), width, height, &data);
+
+            for (target, threshold, measure) in [
+                (QualityTarget::Psnr(36.), 36., metrics::psnr as fn(&RasterImage, &RasterImage) -> f32),
+                (QualityTarget::Ssim(0.95), 0.95, metrics::ssim),
+            ] {
+                let encoding = FRIEncoder::new(EncoderOpts::default())
+                    .unwrap()
+                    .encode_to_quality(data.clone(), height, width, ColorSpace::RGB, target)
+                    .unwrap();
+                assert!(encoding.data.len() < lossless.len());
+
+                let decoded = test_images::decode(encoding.data);
+                let measured = measure(&reference, &decoded);
+                assert!(measured >= threshold, "{measured} < {threshold}");
+                assert_eq!(measured, encoding.achieved);
+            }
         }
     }
 }
@@ -0,0 +1,92 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::io;
+
+use rayon::ThreadPoolBuildError;
+
+use crate::images::Region;
+
+/*
+ * Longest side of an image accepted by the encoder and the decoder. Fractal
+ * coordinates are kept in i32, so it leaves plenty of room for the lattice
+ * spreading past the image borders.
+ */
+pub const MAX_DIMENSION: u32 = 1 << 16;
+
+#[derive(Debug)]
+pub enum FriError {
+    /// Reading or writing the stream failed
+    Io(io::Error),
+    /// Signature or metadata of the image header is not valid
+    InvalidHeader(&'static str),
+    /// Stream ended in the middle of the named segment
+    TruncatedSegment(&'static str),
+    /// Contents of the named segment are inconsistent
+    MalformedSegment(&'static str),
+    /// Stored CRC32 of the named segment does not match its contents
+    ChecksumMismatch(&'static str),
+    /// Stream or options use a feature this version cannot handle
+    Unsupported(&'static str),
+    /// Width or height is zero or larger than MAX_DIMENSION
+    DimensionLimit { width: u32, height: u32 },
+    /// Requested region does not fit into the image
+    InvalidRegion(Region),
+    /// Pixel data or options passed by the caller are not valid
+    InvalidInput(String),
+    /// Pipeline stage failed on otherwise valid input
+    Stage { stage: &'static str, reason: String },
+    /// Worker threads of the encoder or decoder could not be started
+    ThreadPool(ThreadPoolBuildError),
+}
+
+impl Display for FriError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use FriError::*;
+        match self {
+            Io(e) => write!(f, "I/O error: {}", e),
+            InvalidHeader(reason) => write!(f, "Invalid image header: {}", reason),
+            TruncatedSegment(segment) => write!(f, "Stream ends inside of {} segment", segment),
+            MalformedSegment(segment) => write!(f, "Malformed {} segment", segment),
+            ChecksumMismatch(segment) => write!(f, "Checksum mismatch in {}, the stream is corrupted", segment),
+            Unsupported(feature) => write!(f, "Unsupported feature: {}", feature),
+            DimensionLimit { width, height } => write!(
+                f,
+                "Image of {}x{} pixels is outside of the supported range 1..={}",
+                width, height, MAX_DIMENSION
+            ),
+            InvalidRegion(region) => write!(f, "Region {:?} exceeds image bounds", region),
+            InvalidInput(reason) => write!(f, "Invalid input: {}", reason),
+            Stage { stage, reason } => write!(f, "{} failed: {}", stage, reason),
+            ThreadPool(e) => write!(f, "Cannot start worker threads: {}", e),
+        }
+    }
+}
+
+impl Error for FriError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FriError::Io(e) => Some(e),
+            FriError::ThreadPool(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FriError {
+    fn from(err: io::Error) -> Self {
+        FriError::Io(err)
+    }
+}
+
+impl From<ThreadPoolBuildError> for FriError {
+    fn from(err: ThreadPoolBuildError) -> Self {
+        FriError::ThreadPool(err)
+    }
+}
+
+pub fn check_dimensions(width: u32, height: u32) -> Result<(), FriError> {
+    if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(FriError::DimensionLimit { width, height });
+    }
+    Ok(())
+}
@@ -1,87 +1,107 @@
+use std::sync::LazyLock;
+
 use num::complex::Complex;
 
-//fn get_literals<const N: usize>(d: f32) -> [Complex<f32>; N] {
-//    let base = Complex::new(d / 2., (2. - (d / 2.).powf(2.)).sqrt());
-//    let mut powers = [base; N];
-//    let mut i = 1;
-//
-//    let mut pow = Complex::<f32> { re: 1., im: 0. };
-//    while i < N {
-//        powers[i] = Complex::<f32> {
-//            re: (-pow.re / base.re).round(),
-//            im: (pow.im / base.im).round(),
-//        };
-//        pow *= base;
-//        i += 1;
-//    }
-//
-//    powers[0] = Complex::<f32> { re: 0., im: 1. };
-//
-//    powers.swap(1, 2);
-//
-//    powers
-//}
+use crate::images::FractalVariant;
+
+const LITERAL_AMOUNT: usize = 30;
 
+/*
+ * Real part of the doubled base of tame twindragon, the base itself is
+ * 1/2 + i * sqrt(7) / 2, which makes it a root of z^2 - z + 2.
+ */
+pub const TAME_TWINDRAGON_BASE: i32 = 1;
 
+/*
+ * LITERALS are the offsets between the halves of fractal on consecutive levels.
+ * Every fractal of depth d consists of points center + sum of a subset of first d literals.
+ * No table of lattice centers goes with them, the lattice grows from the middle of the
+ * image through every fractal touching it, see WaveletImage::fractal_divide, so images
+ * of any size are covered without a single fractal enclosing them.
+ */
+pub static LITERALS: LazyLock<Vec<Complex<i32>>> =
+    LazyLock::new(|| get_literals(TAME_TWINDRAGON_BASE, LITERAL_AMOUNT));
 
 /*
- * CENTERS represent the optimal center of fractal space for biggest rectangle that has specific dimensions
- * Calculation can be found in fractal_lattice repo. This is statically encoded in codec
- * to save it from doing costly calculations in runtime. Unfortunately Rust const functions are
- * not yet sophisticated enough to calculate these numbers in compile time
+ * Powers of the twindragon base -1 + i. They are gaussian integers, so unlike tame
+ * twindragon no change of coordinates is needed to place them on image pixels.
  */
-pub static CENTERS: [((i32, i32), Complex<i32>, u8); 15] = [
-    ((17,8), Complex { re: 1, im: 2}, 9),
-    ((47,9), Complex { re: 31, im: 1}, 10),
-    ((41,26), Complex { re: 26, im: 18}, 11),
-    ((88,15), Complex { re: 21, im: 7}, 12),
-    ((108,65), Complex { re: 88, im: 43}, 14),
-    ((227,60), Complex { re: 82, im: 41}, 15),
-    ((202,149), Complex { re: 88, im: 40}, 16),
-    ((284,84), Complex { re: 266, im: 52}, 17),
-    ((649,148), Complex { re: 246, im: 130}, 18),
-    ((651,418), Complex { re: 175, im: 130}, 19),
-    ((1542, 333), Complex { re: 1120, im: 130}, 20),
-    ((997,458), Complex { re: 449, im: 421}, 21),
-    ((1148,883), Complex { re: 74, im: 320}, 22),
-    ((4243,960), Complex { re: 2869, im: 215}, 23),
-    ((3648,2439), Complex { re: 2375, im: 1725}, 24),
-];
+pub static TWINDRAGON_LITERALS: LazyLock<Vec<Complex<i32>>> = LazyLock::new(|| {
+    std::iter::successors(Some(Complex::new(1, 0)), |power| Some(power * Complex::new(-1, 1)))
+        .take(LITERAL_AMOUNT)
+        .collect()
+});
+
+/*
+ * Splits alternating between halving the width and the height, a fractal of even
+ * depth is a square block and the transform becomes the conventional 2D Haar.
+ */
+pub static BOXES_LITERALS: LazyLock<Vec<Complex<i32>>> = LazyLock::new(|| {
+    (0..LITERAL_AMOUNT)
+        .map(|k| {
+            if k % 2 == 0 {
+                Complex::new(1 << (k / 2), 0)
+            } else {
+                Complex::new(0, 1 << (k / 2))
+            }
+        })
+        .collect()
+});
+
+impl FractalVariant {
+    pub fn get_literals(&self) -> &'static [Complex<i32>] {
+        match self {
+            FractalVariant::TameTwindragon => &LITERALS,
+            FractalVariant::Twindragon => &TWINDRAGON_LITERALS,
+            FractalVariant::Boxes => &BOXES_LITERALS,
+        }
+    }
+}
+
+/*
+ * Computes literals for a base b = d / 2 + i * sqrt(2 - d^2 / 4), which has norm 2 for
+ * every d, so multiplying by it doubles the area covered by the fractal. A point
+ * u * Re(b) + i * v * Im(b) is stored as (u, v), in these coordinates multiplication by
+ * -conj(b) stays on integers and powers of the base land exactly on image pixels.
+ * First literal steps along the imaginary axis, the remaining ones are the powers with
+ * the first two swapped.
+ */
+pub fn get_literals(d: i32, count: usize) -> Vec<Complex<i32>> {
+    let mut powers = Vec::with_capacity(count);
+    let mut power = Complex::new(2, 0);
+    while powers.len() + 1 < count {
+        powers.push(power);
+        power = Complex::new(
+            -(power.re * d * d + power.im * (8 - d * d)) / (2 * d),
+            (power.re - power.im) * d / 2,
+        );
+    }
+    if powers.len() > 1 {
+        powers.swap(0, 1);
+    }
+
+    let mut literals = vec![Complex::new(0, 1)];
+    literals.extend(powers);
+    literals.truncate(count);
+    literals
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
 
-pub static LITERALS: [Complex<i32>; 30] = 
-             [
-               Complex { re: 0, im: 1 },
-               Complex { re: -1, im: 1 },
-               Complex { re: 2, im: 0 },
-               Complex { re: -3, im: -1 },
-               Complex { re: 5, im: -1 },
-               Complex { re: 1, im: 3 },
-               Complex { re: -11, im: -1 },
-               Complex { re: 9, im: -5 },
-               Complex { re: 13, im: 7 },
-               Complex { re: -31, im: 3 },
-               Complex { re: 5, im: -17 },
-               Complex { re: 57, im: 11 },
-               Complex { re: -67, im: 23 },
-               Complex { re: -47, im: -45 },
-               Complex { re: 181, im: -1 },
-               Complex { re: -87, im: 91 },
-               Complex { re: -275, im: -89 },
-               Complex { re: 449, im: -93 },
-               Complex { re: 101, im: 271 },
-               Complex { re: -999, im: -85 },
-               Complex { re: 797, im: -457 },
-               Complex { re: 1201, im: 627 },
-               Complex { re: -2795, im: 287 },
-               Complex { re: 393, im: -1541 },
-               Complex { re: 5197, im: 967 },
-               Complex { re: -5983, im: 2115 },
-               Complex { re: -4411, im: -4049 },
-               Complex { re: 16377, im: -181 },
-               Complex { re: -7555, im: 8279 },
-               Complex {
-                    re: -25199,
-                    im: -7917,
-                },
-            ];
+    static EXPECTED_LITERALS: [(i32, i32); LITERAL_AMOUNT] = [
+        (0, 1), (-1, 1), (2, 0), (-3, -1), (5, -1), (1, 3), (-11, -1), (9, -5), (13, 7),
+        (-31, 3), (5, -17), (57, 11), (-67, 23), (-47, -45), (181, -1), (-87, 91),
+        (-275, -89), (449, -93), (101, 271), (-999, -85), (797, -457), (1201, 627),
+        (-2795, 287), (393, -1541), (5197, 967), (-5983, 2115), (-4411, -4049),
+        (16377, -181), (-7555, 8279), (-25199, -7917),
+    ];
 
+    #[test]
+    fn literals_reproduce_table() {
+        let expected: Vec<Complex<i32>> =
+            EXPECTED_LITERALS.iter().map(|&(re, im)| Complex::new(re, im)).collect();
+        assert_eq!(*LITERALS, expected);
+    }
+}
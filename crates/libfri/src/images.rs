@@ -1,6 +1,6 @@
-use crate::fractal::LITERALS;
 use crate::stages::entropy_coding::AnsContext;
-use crate::stages::serialize::SerializeError;
+use crate::stages::quantization::QUANTIZATION_LAYERS;
+use crate::error::FriError;
 use crate::stages::wavelet_transform::WaveletImage;
 use num::complex::Complex;
 
@@ -20,6 +20,28 @@ impl ColorSpace {
         }
     }
 
+    /*
+     * Bytes per sample in the raster. Chroma of transformed images spans 9 bits,
+     * so YCbCr samples are stored as little endian i16.
+     */
+    pub fn sample_size(&self) -> usize {
+        match self {
+            ColorSpace::YCbCr => 2,
+            _ => 1,
+        }
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.num_channels() * self.sample_size()
+    }
+
+    fn sample_range(&self, channel: usize) -> (i32, i32) {
+        match (self, channel) {
+            (ColorSpace::YCbCr, 1 | 2) => (-255, 255),
+            _ => (0, 255),
+        }
+    }
+
     pub fn get_encoding(&self) -> u32 {
         match self {
             ColorSpace::Luma => 0b01,
@@ -28,17 +50,17 @@ impl ColorSpace {
         }
     }
 
-    pub fn from_encoding(val: u8) -> Result<ColorSpace, SerializeError> {
+    pub fn from_encoding(val: u8) -> Result<ColorSpace, FriError> {
         match val {
             0b01 => Ok(ColorSpace::Luma),
             0b10 => Ok(ColorSpace::RGB),
             0b11 => Ok(ColorSpace::YCbCr),
-            _ => Err(SerializeError::InvalidMetadata),
+            _ => Err(FriError::InvalidHeader("unknown colorspace")),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum FractalVariant {
     TameTwindragon,
     Twindragon,
@@ -54,27 +76,94 @@ impl FractalVariant {
         }
     }
 
-    pub fn from_encoding(val: u8) -> Result<FractalVariant, SerializeError> {
+    pub fn from_encoding(val: u8) -> Result<FractalVariant, FriError> {
         match val {
             0b01 => Ok(FractalVariant::TameTwindragon),
             0b10 => Ok(FractalVariant::Twindragon),
             0b11 => Ok(FractalVariant::Boxes),
-            _ => Err(SerializeError::InvalidMetadata),
+            _ => Err(FriError::InvalidHeader("unknown fractal variant")),
         }
     }
 }
 
+/*
+ * Coder of the residual streams, rANS decodes faster and the adaptive binary
+ * arithmetic coder gives smaller images.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EntropyCoder {
+    #[default]
+    Rans,
+    Binary,
+}
+
+/*
+ * Color profile and descriptive metadata of the image, stored and returned
+ * unchanged. EXIF holds the TIFF structure without the APP1 or eXIf wrapping,
+ * XMP the serialized XML packet.
+ */
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct EmbeddedMetadata {
+    pub icc_profile: Option<Vec<u8>>,
+    pub exif: Option<Vec<u8>>,
+    pub xmp: Option<Vec<u8>>,
+}
+
 #[derive(Clone)]
 pub struct ImageMetadata {
     pub height: u32,
     pub width: u32,
     pub colorspace: ColorSpace,
     pub variant: FractalVariant,
+    pub embedded: EmbeddedMetadata,
 }
 
 impl ImageMetadata {
     pub fn new(height: u32, width: u32) -> Self {
-        ImageMetadata { height, width, colorspace: ColorSpace::RGB, variant: FractalVariant::TameTwindragon }
+        ImageMetadata {
+            height,
+            width,
+            colorspace: ColorSpace::RGB,
+            variant: FractalVariant::TameTwindragon,
+            embedded: EmbeddedMetadata::default(),
+        }
+    }
+}
+
+/*
+ * Rectangle of pixels, used to decode only a part of the image.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    #[inline]
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x as i32
+            && y >= self.y as i32
+            && x < (self.x + self.width) as i32
+            && y < (self.y + self.height) as i32
+    }
+
+    pub fn fits(&self, width: u32, height: u32) -> bool {
+        self.x as u64 + self.width as u64 <= width as u64
+            && self.y as u64 + self.height as u64 <= height as u64
+    }
+
+    pub fn intersection(&self, other: &Region) -> Option<Region> {
+        let (x0, y0) = (self.x.max(other.x), self.y.max(other.y));
+        let x1 = (self.x + self.width).min(other.x + other.width);
+        let y1 = (self.y + self.height).min(other.y + other.height);
+        if x0 < x1 && y0 < y1 {
+            Some(Region { x: x0, y: y0, width: x1 - x0, height: y1 - y0 })
+        } else {
+            None
+        }
     }
 }
 
@@ -86,27 +175,68 @@ pub struct RasterImage {
 
 impl RasterImage {
     #[inline]
-    pub fn get_pixel(&self, x: i32, y: i32, channel: usize) -> Option<i32> {
+    fn sample_position(&self, x: i32, y: i32, channel: usize) -> Option<usize> {
         if x >= 0 && y >= 0 && x < self.metadata.width as i32 && y < self.metadata.height as i32 {
-            let (x, y): (u32, u32) = (x.try_into().unwrap(), y.try_into().unwrap());
-            let num_channels = self.metadata.colorspace.num_channels() as u32;
+            let colorspace = &self.metadata.colorspace;
             // TODO: Add different subsamplings, currently 4:4:4 is supported
-            let position = ((y * self.metadata.width + x) * num_channels + channel as u32) as usize;
-            let value = self.data[position];
-            Some(i32::from(value))
+            let pixel = y as usize * self.metadata.width as usize + x as usize;
+            Some((pixel * colorspace.num_channels() + channel) * colorspace.sample_size())
         } else {
             None
         }
     }
 
+    #[inline]
+    pub fn get_pixel(&self, x: i32, y: i32, channel: usize) -> Option<i32> {
+        let position = self.sample_position(x, y, channel)?;
+        match self.metadata.colorspace.sample_size() {
+            1 => Some(i32::from(self.data[position])),
+            _ => Some(i32::from(i16::from_le_bytes([self.data[position], self.data[position + 1]]))),
+        }
+    }
+
     #[inline]
     pub fn set_pixel(&mut self, x: i32, y: i32, value: i32, channel: usize) {
-        if x >= 0 && y >= 0 && x < self.metadata.width as i32 && y < self.metadata.height as i32 {
-            let (x, y): (u32, u32) = (x.try_into().unwrap(), y.try_into().unwrap());
-            let num_channels = self.metadata.colorspace.num_channels() as u32;
-            // TODO: Add different subsamplings, currently 4:4:4 is supported
-            let position = ((y * self.metadata.width + x) * num_channels + channel as u32) as usize;
-            self.data[position] = value.clamp(0, 255) as u8;
+        if let Some(position) = self.sample_position(x, y, channel) {
+            let (min, max) = self.metadata.colorspace.sample_range(channel);
+            let value = value.clamp(min, max);
+            match self.metadata.colorspace.sample_size() {
+                1 => self.data[position] = value as u8,
+                _ => self.data[position..position + 2].copy_from_slice(&(value as i16).to_le_bytes()),
+            }
+        }
+    }
+
+    pub fn crop(&self, region: &Region) -> Result<RasterImage, FriError> {
+        if !region.fits(self.metadata.width, self.metadata.height) {
+            return Err(FriError::InvalidRegion(*region));
+        }
+
+        let pixel_size = self.metadata.colorspace.bytes_per_pixel();
+        let row_len = region.width as usize * pixel_size;
+        let mut data = Vec::with_capacity(row_len * region.height as usize);
+        for y in region.y..region.y + region.height {
+            let start = (y as usize * self.metadata.width as usize + region.x as usize) * pixel_size;
+            data.extend_from_slice(&self.data[start..start + row_len]);
+        }
+
+        let mut metadata = self.metadata.clone();
+        metadata.width = region.width;
+        metadata.height = region.height;
+        Ok(RasterImage { metadata, data })
+    }
+
+    /*
+     * Copies the whole image into this one with its top left corner at (x, y),
+     * both images have to share the colorspace.
+     */
+    pub fn paste(&mut self, image: &RasterImage, x: u32, y: u32) {
+        let pixel_size = self.metadata.colorspace.bytes_per_pixel();
+        let row_len = image.metadata.width as usize * pixel_size;
+        for row in 0..image.metadata.height {
+            let src = row as usize * row_len;
+            let dst = ((y + row) as usize * self.metadata.width as usize + x as usize) * pixel_size;
+            self.data[dst..dst + row_len].copy_from_slice(&image.data[src..src + row_len]);
         }
     }
 }
@@ -114,11 +244,54 @@ impl RasterImage {
 pub struct ChannelData {
    pub ans_contexts: Vec<AnsContext>,
    pub data: Vec<u8>,
+   // Start of every fractal group stream within data
+   pub group_offsets: Vec<usize>,
    pub value_prediction_parameters: Vec<[f32;6]>,
    pub width_prediction_parameters: Vec<[f32;6]>,
 }
 
+pub struct EncodedTile {
+    pub region: Region,
+    pub data: Vec<u8>,
+}
+
+/*
+ * Image split into tiles, every tile is a complete serialized image coded
+ * with its own fractal lattice.
+ */
+pub struct TiledImage {
+    pub metadata: ImageMetadata,
+    pub tiles: Vec<EncodedTile>,
+}
+
+/*
+ * Frame of an image sequence shown for duration_ms milliseconds.
+ */
+pub struct Frame {
+    pub image: RasterImage,
+    pub duration_ms: u32,
+}
+
+pub struct EncodedFrame {
+    pub duration_ms: u32,
+    // Coefficients are coded as differences to the previous frame
+    pub predicted: bool,
+    pub data: Vec<u8>,
+}
+
+/*
+ * Sequence of frames sharing dimensions and fractal lattice, every frame is a
+ * complete serialized image.
+ */
+pub struct SequenceImage {
+    pub metadata: ImageMetadata,
+    pub frames: Vec<EncodedFrame>,
+}
+
 pub struct CompressedImage {
     pub metadata: ImageMetadata,
     pub channel_data: [Option<ChannelData>; 3],
+    pub quantization_matrix: [i32; QUANTIZATION_LAYERS],
+    pub group_size: u32,
+    pub entropy_coder: EntropyCoder,
 }
@@ -1,10 +1,16 @@
 #![allow(warnings)]
 
 pub mod encoder;
+pub mod error;
 pub mod decoder;
 pub mod images;
+pub mod metrics;
 mod context_modeling;
 mod stage;
 mod fractal;
 mod stages;
 mod utils;
+#[cfg(feature = "bench")]
+pub mod bench;
+#[cfg(test)]
+mod test_images;
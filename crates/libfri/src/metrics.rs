@@ -0,0 +1,91 @@
+use crate::images::RasterImage;
+
+const SSIM_WINDOW: u32 = 8;
+const SSIM_STRIDE: u32 = 4;
+const SSIM_C1: f64 = (0.01 * 255.) * (0.01 * 255.);
+const SSIM_C2: f64 = (0.03 * 255.) * (0.03 * 255.);
+
+pub fn psnr(reference: &RasterImage, distorted: &RasterImage) -> f32 {
+    let squared_error: u64 = reference
+        .data
+        .iter()
+        .zip(distorted.data.iter())
+        .map(|(&a, &b)| (a as i64 - b as i64).pow(2) as u64)
+        .sum();
+
+    if squared_error == 0 {
+        return f32::INFINITY;
+    }
+    let mse = squared_error as f64 / reference.data.len() as f64;
+    (10. * (255. * 255. / mse).log10()) as f32
+}
+
+fn window_ssim(reference: &RasterImage, distorted: &RasterImage, x0: u32, y0: u32, channel: usize) -> f64 {
+    let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0., 0., 0., 0., 0.);
+    for y in y0..y0 + SSIM_WINDOW {
+        for x in x0..x0 + SSIM_WINDOW {
+            let a = reference.get_pixel(x as i32, y as i32, channel).unwrap() as f64;
+            let b = distorted.get_pixel(x as i32, y as i32, channel).unwrap() as f64;
+            sum_a += a;
+            sum_b += b;
+            sum_aa += a * a;
+            sum_bb += b * b;
+            sum_ab += a * b;
+        }
+    }
+
+    let n = (SSIM_WINDOW * SSIM_WINDOW) as f64;
+    let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+    let var_a = sum_aa / n - mean_a * mean_a;
+    let var_b = sum_bb / n - mean_b * mean_b;
+    let covariance = sum_ab / n - mean_a * mean_b;
+
+    ((2. * mean_a * mean_b + SSIM_C1) * (2. * covariance + SSIM_C2))
+        / ((mean_a * mean_a + mean_b * mean_b + SSIM_C1) * (var_a + var_b + SSIM_C2))
+}
+
+/*
+ * Mean SSIM over 8x8 windows placed every 4 pixels, averaged over all channels.
+ * Images smaller than a single window are compared as if they were identical
+ * whenever their PSNR is infinite.
+ */
+pub fn ssim(reference: &RasterImage, distorted: &RasterImage) -> f32 {
+    let metadata = &reference.metadata;
+    if metadata.width < SSIM_WINDOW || metadata.height < SSIM_WINDOW {
+        return if psnr(reference, distorted).is_infinite() { 1. } else { 0. };
+    }
+
+    let mut total = 0.;
+    let mut windows = 0;
+    for channel in 0..metadata.colorspace.num_channels() {
+        for y in (0..=metadata.height - SSIM_WINDOW).step_by(SSIM_STRIDE as usize) {
+            for x in (0..=metadata.width - SSIM_WINDOW).step_by(SSIM_STRIDE as usize) {
+                total += window_ssim(reference, distorted, x, y, channel);
+                windows += 1;
+            }
+        }
+    }
+    (total / windows as f64) as f32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_images::{gradient, raster};
+
+    #[test]
+    fn identical_images() {
+        let image = raster(16, 12, gradient(16, 12));
+        assert!(psnr(&image, &image).is_infinite());
+        assert!((ssim(&image, &image) - 1.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn distortion_lowers_metrics() {
+        let image = raster(16, 12, gradient(16, 12));
+        let mut distorted = image.clone();
+        distorted.data.iter_mut().step_by(2).for_each(|v| *v = v.saturating_add(8));
+        assert!(psnr(&image, &distorted) < 40.);
+        assert!(ssim(&image, &distorted) < 1.);
+    }
+}
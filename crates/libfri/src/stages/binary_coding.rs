@@ -0,0 +1,318 @@
+use crate::stages::entropy_coding::{AnsContext, EntropyBackend, ResidualDecoder, ResidualEncoder};
+
+/*
+ * Adaptive binary arithmetic coding of residuals. A packed residual is binarized
+ * into its bit length, coded in unary, and the bits below its leading one. The
+ * unary bits and the top mantissa bits have adaptive probabilities per context,
+ * lower mantissa bits are coded as they are. Slower than rANS, but probabilities
+ * follow the image closely and nothing about them is stored in the stream.
+ */
+pub struct BinaryBackend;
+
+const PROBABILITY_BITS: u32 = 12;
+const ADAPTATION_SHIFT: u32 = 5;
+const TOP: u32 = 1 << 24;
+
+// Mantissa bits below the leading one with probabilities of their own
+const MODELED_MANTISSA_BITS: u32 = 3;
+const CLASSES: usize = u32::BITS as usize + 1;
+
+/*
+ * Probability of a zero bit in units of 2^-PROBABILITY_BITS.
+ */
+#[derive(Clone, Copy)]
+struct BitModel(u32);
+
+impl BitModel {
+    fn new(zero_probability: f64) -> Self {
+        let total = (1 << PROBABILITY_BITS) as f64;
+        BitModel((zero_probability * total).clamp(32., total - 32.) as u32)
+    }
+
+    fn update(&mut self, bit: u32) {
+        match bit {
+            0 => self.0 += ((1 << PROBABILITY_BITS) - self.0) >> ADAPTATION_SHIFT,
+            _ => self.0 -= self.0 >> ADAPTATION_SHIFT,
+        }
+    }
+}
+
+struct ResidualModel {
+    // classes[k] codes whether the bit length of the residual is above k
+    classes: [BitModel; CLASSES],
+    // Binary tree over the modeled mantissa bits of every bit length
+    mantissa: [[BitModel; 1 << MODELED_MANTISSA_BITS]; CLASSES],
+}
+
+impl ResidualModel {
+    /*
+     * Starts the bit length probabilities from the Laplace distribution of the
+     * context, residual x has probability proportional to r^|x| with r = e^(-1/width).
+     */
+    fn new(context: &AnsContext) -> Self {
+        let r = (-1. / context.width as f64).exp();
+        let tail = |a: u64| r.powf(a as f64) / (1. + r);
+        // Probability of a packed residual of at least t
+        let at_least = |t: u64| match t {
+            0 => 1.,
+            _ => tail(t.div_ceil(2)) + tail((t + 1).div_ceil(2)),
+        };
+
+        let mut classes = [BitModel::new(0.5); CLASSES];
+        for (k, model) in classes.iter_mut().enumerate().take(CLASSES - 1) {
+            let reached = if k == 0 { 1. } else { at_least(1 << (k - 1)) };
+            let above = at_least(1 << k);
+            if reached > 0. {
+                *model = BitModel::new(1. - above / reached);
+            }
+        }
+        ResidualModel { classes, mantissa: [[BitModel::new(0.5); 1 << MODELED_MANTISSA_BITS]; CLASSES] }
+    }
+}
+
+fn bit_length(value: u32) -> usize {
+    (u32::BITS - value.leading_zeros()) as usize
+}
+
+struct RangeEncoder {
+    low: u64,
+    range: u32,
+    cache: u8,
+    cache_size: u64,
+    data: Vec<u8>,
+}
+
+impl RangeEncoder {
+    fn shift_low(&mut self) {
+        if (self.low as u32) < 0xFF00_0000 || (self.low >> 32) != 0 {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            while self.cache_size != 0 {
+                self.data.push(byte.wrapping_add(carry));
+                byte = 0xFF;
+                self.cache_size -= 1;
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00FF_FFFF) << 8;
+    }
+
+    fn normalize(&mut self) {
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    fn encode_bit(&mut self, model: &mut BitModel, bit: u32) {
+        let bound = (self.range >> PROBABILITY_BITS) * model.0;
+        match bit {
+            0 => self.range = bound,
+            _ => {
+                self.low += bound as u64;
+                self.range -= bound;
+            }
+        }
+        model.update(bit);
+        self.normalize();
+    }
+
+    fn encode_direct(&mut self, value: u32, bits: u32) {
+        for shift in (0..bits).rev() {
+            self.range >>= 1;
+            if value >> shift & 1 == 1 {
+                self.low += self.range as u64;
+            }
+            self.normalize();
+        }
+    }
+}
+
+pub struct BinaryEncoder {
+    models: Vec<ResidualModel>,
+    coder: RangeEncoder,
+}
+
+impl ResidualEncoder for BinaryEncoder {
+    fn encode(&mut self, bucket: usize, residual: u32) {
+        let (model, coder) = (&mut self.models[bucket], &mut self.coder);
+        let class = bit_length(residual);
+        for k in 0..class {
+            coder.encode_bit(&mut model.classes[k], 1);
+        }
+        if class < CLASSES - 1 {
+            coder.encode_bit(&mut model.classes[class], 0);
+        }
+
+        if class > 1 {
+            let mantissa_bits = class as u32 - 1;
+            let modeled = mantissa_bits.min(MODELED_MANTISSA_BITS);
+            let mut node = 1;
+            for shift in (mantissa_bits - modeled..mantissa_bits).rev() {
+                let bit = residual >> shift & 1;
+                coder.encode_bit(&mut model.mantissa[class][node], bit);
+                node = node * 2 + bit as usize;
+            }
+            coder.encode_direct(residual, mantissa_bits - modeled);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for _ in 0..5 {
+            self.coder.shift_low();
+        }
+        self.coder.data
+    }
+}
+
+/*
+ * Missing data reads as zeros, so truncated streams still decode to some values.
+ */
+struct RangeDecoder {
+    code: u32,
+    range: u32,
+    data: Vec<u8>,
+    position: usize,
+}
+
+impl RangeDecoder {
+    fn read_byte(&mut self) -> u32 {
+        let byte = self.data.get(self.position).copied().unwrap_or(0);
+        self.position += 1;
+        byte as u32
+    }
+
+    fn normalize(&mut self) {
+        while self.range < TOP {
+            self.range <<= 8;
+            self.code = (self.code << 8) | self.read_byte();
+        }
+    }
+
+    fn decode_bit(&mut self, model: &mut BitModel) -> u32 {
+        let bound = (self.range >> PROBABILITY_BITS) * model.0;
+        let bit = match self.code < bound {
+            true => {
+                self.range = bound;
+                0
+            }
+            false => {
+                self.code -= bound;
+                self.range -= bound;
+                1
+            }
+        };
+        model.update(bit);
+        self.normalize();
+        bit
+    }
+
+    fn decode_direct(&mut self, bits: u32) -> u32 {
+        let mut value = 0;
+        for _ in 0..bits {
+            self.range >>= 1;
+            let bit = (self.code >= self.range) as u32;
+            if bit == 1 {
+                self.code -= self.range;
+            }
+            value = (value << 1) | bit;
+            self.normalize();
+        }
+        value
+    }
+}
+
+pub struct BinaryDecoder {
+    models: Vec<ResidualModel>,
+    coder: RangeDecoder,
+}
+
+impl ResidualDecoder for BinaryDecoder {
+    fn decode(&mut self, bucket: usize) -> u32 {
+        let (model, coder) = (&mut self.models[bucket], &mut self.coder);
+        let mut class = 0;
+        while class < CLASSES - 1 && coder.decode_bit(&mut model.classes[class]) == 1 {
+            class += 1;
+        }
+
+        let mut residual = 0;
+        if class > 0 {
+            residual = 1;
+        }
+        if class > 1 {
+            let mantissa_bits = class as u32 - 1;
+            let modeled = mantissa_bits.min(MODELED_MANTISSA_BITS);
+            let mut node = 1;
+            for _ in 0..modeled {
+                let bit = coder.decode_bit(&mut model.mantissa[class][node]);
+                node = node * 2 + bit as usize;
+                residual = (residual << 1) | bit;
+            }
+            let direct = mantissa_bits - modeled;
+            residual = (residual << direct) | coder.decode_direct(direct);
+        }
+        residual
+    }
+}
+
+impl EntropyBackend for BinaryBackend {
+    type Encoder<'a> = BinaryEncoder;
+    type Decoder<'a> = BinaryDecoder;
+
+    fn encoder(contexts: &[AnsContext]) -> BinaryEncoder {
+        BinaryEncoder {
+            models: contexts.iter().map(ResidualModel::new).collect(),
+            coder: RangeEncoder { low: 0, range: u32::MAX, cache: 0, cache_size: 1, data: vec![] },
+        }
+    }
+
+    fn decoder(contexts: &[AnsContext], data: Vec<u8>) -> BinaryDecoder {
+        let mut coder = RangeDecoder { code: 0, range: u32::MAX, data, position: 0 };
+        for _ in 0..5 {
+            coder.code = (coder.code << 8) | coder.read_byte();
+        }
+        BinaryDecoder { models: contexts.iter().map(ResidualModel::new).collect(), coder }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn residuals_round_trip() {
+        let contexts = [AnsContext::for_bucket(0, 1.5), AnsContext::for_bucket(4, 30.)];
+        let residuals: Vec<(usize, u32)> = (0..2000u32)
+            .map(|i| ((i % 2) as usize, (i * 7919) % 40))
+            .chain([(0, 0), (1, 1 << 20), (0, u32::MAX), (1, 2), (0, 1 << 31)])
+            .collect();
+
+        let mut encoder = BinaryBackend::encoder(&contexts);
+        for &(bucket, residual) in &residuals {
+            encoder.encode(bucket, residual);
+        }
+        let mut decoder = BinaryBackend::decoder(&contexts, encoder.finish());
+        for &(bucket, residual) in &residuals {
+            assert_eq!(decoder.decode(bucket), residual);
+        }
+    }
+
+    #[test]
+    fn images_round_trip_losslessly() {
+        use crate::encoder::EncoderOpts;
+        use crate::images::EntropyCoder;
+        use crate::test_images::{self, noisy_gradient, saturated};
+
+        // Noise spreads residuals over every context, saturated blocks give the
+        // largest chroma residuals
+        let (width, height) = (41, 29);
+        for data in [noisy_gradient(width, height, 63), saturated(width, height)] {
+            for tile_size in [None, Some(16)] {
+                let opts = EncoderOpts { tile_size, entropy_coder: EntropyCoder::Binary, ..Default::default() };
+                let decoded = test_images::round_trip(opts, width, height, &data);
+                assert!(decoded.data == data, "tiles {:?}", tile_size);
+            }
+        }
+    }
+}
@@ -1,10 +1,98 @@
-use crate::images::RasterImage;
+use crate::images::{ColorSpace, RasterImage};
+
+/*
+ * Reversible YCoCg-R transform. Co and Cg span [-255, 255], so the transformed
+ * raster keeps them as 16-bit samples, see ColorSpace::sample_size. Quantized
+ * chroma is clamped to that range and never wraps around.
+ */
+fn forward_ycocg_r(r: i32, g: i32, b: i32) -> (i32, i32, i32) {
+    let co = r - b;
+    let t = b + (co >> 1);
+    let cg = g - t;
+    let y = t + (cg >> 1);
+    (y, co, cg)
+}
+
+fn inverse_ycocg_r(y: i32, co: i32, cg: i32) -> (i32, i32, i32) {
+    let t = y - (cg >> 1);
+    let g = cg + t;
+    let b = t - (co >> 1);
+    let r = b + co;
+    (r, g, b)
+}
 
-// TODO Add YrCrBr
 pub fn encode(image: RasterImage) -> Result<RasterImage, String> {
-    Ok(image)
+    match image.metadata.colorspace {
+        ColorSpace::Luma => Ok(image),
+        ColorSpace::RGB => {
+            let mut metadata = image.metadata;
+            metadata.colorspace = ColorSpace::YCbCr;
+            let mut data = Vec::with_capacity(image.data.len() * 2);
+            for pixel in image.data.chunks_exact(3) {
+                let (y, co, cg) =
+                    forward_ycocg_r(pixel[0].into(), pixel[1].into(), pixel[2].into());
+                for value in [y, co, cg] {
+                    data.extend_from_slice(&(value as i16).to_le_bytes());
+                }
+            }
+            Ok(RasterImage { metadata, data })
+        }
+        ColorSpace::YCbCr => Err(String::from(
+            "YCbCr is reserved for transformed RGB images, expected RGB or Luma input",
+        )),
+    }
 }
 
 pub fn decode(image: RasterImage) -> Result<RasterImage, String> {
+    if let ColorSpace::YCbCr = image.metadata.colorspace {
+        let mut metadata = image.metadata;
+        metadata.colorspace = ColorSpace::RGB;
+        let mut data = Vec::with_capacity(image.data.len() / 2);
+        for pixel in image.data.chunks_exact(6) {
+            let sample = |i: usize| i32::from(i16::from_le_bytes([pixel[2 * i], pixel[2 * i + 1]]));
+            let (r, g, b) = inverse_ycocg_r(sample(0), sample(1), sample(2));
+            data.extend([r, g, b].map(|value| value.clamp(0, 255) as u8));
+        }
+        return Ok(RasterImage { metadata, data });
+    }
     Ok(image)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::images::ImageMetadata;
+
+    #[test]
+    fn ycocg_r_is_reversible() {
+        for r in (0..=255).step_by(3) {
+            for g in (0..=255).step_by(5) {
+                for b in 0..=255 {
+                    let (y, co, cg) = forward_ycocg_r(r, g, b);
+                    assert_eq!(inverse_ycocg_r(y, co, cg), (r, g, b));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn grey_maps_to_neutral_chroma() {
+        assert_eq!(forward_ycocg_r(77, 77, 77), (77, 0, 0));
+    }
+
+    #[test]
+    fn saturated_chroma_does_not_wrap() {
+        let mut image = RasterImage {
+            metadata: ImageMetadata::new(1, 2),
+            data: vec![255, 0, 0, 0, 0, 255],
+        };
+        image = encode(image).unwrap();
+        assert_eq!(image.get_pixel(0, 0, 1), Some(255));
+        assert_eq!(image.get_pixel(1, 0, 1), Some(-255));
+
+        // A quantization error pushing chroma past its range saturates instead of wrapping
+        image.set_pixel(0, 0, 300, 1);
+        image.set_pixel(1, 0, -260, 1);
+        assert_eq!(decode(image).unwrap().data, vec![255, 0, 0, 0, 0, 255]);
+    }
+}
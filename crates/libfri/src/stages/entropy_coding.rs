@@ -1,28 +1,82 @@
+use crate::decoder::DecoderOpts;
 use crate::encoder::EncoderOpts;
-use crate::images::{ChannelData, CompressedImage};
+use crate::error::FriError;
+use crate::images::{ChannelData, CompressedImage, EntropyCoder};
 use crate::stages::prediction;
-use crate::stages::wavelet_transform::{Fractal, WaveletImage};
+use crate::stages::wavelet_transform::{Fractal, WaveletImage, BASE_FRAC_DEPTH};
 use crate::{fractal, utils};
 
 use core::f32;
 use num::Complex;
+use rayon::prelude::*;
 use std::collections::HashMap;
 use std::fmt::write;
 use std::fs::File;
 use std::io::Write;
 use std::usize;
 
-use rans::b64_decoder::{B64RansDecSymbol, B64RansDecoderMulti};
 use rans::b64_encoder::{B64RansEncSymbol, B64RansEncoderMulti};
-use rans::RansDecoderMulti;
 use rans::RansEncoderMulti;
 use rans::{RansDecSymbol, RansEncSymbol};
 
-use crate::stages::prediction::CONTEXT_AMOUNT;
+use crate::stages::binary_coding::BinaryBackend;
+use crate::stages::prediction::{assign_bucket, MAX_CONTEXTS};
 
-use super::prediction::{get_width_from_bucket, laplace_distribution};
+use super::prediction::laplace_distribution;
 
-pub const ALPHABET_SIZE: usize = 1024;
+/*
+ * Residuals are coded as tokens. Packed residuals below DIRECT_TOKENS are their own
+ * token, larger ones get a token for their bit length followed by the bits below the
+ * leading one, which are written raw into the same rANS stream. Raw bits never take
+ * part in the distributions, so any i32 residual is representable.
+ */
+pub const DIRECT_TOKENS: usize = 1024;
+const DIRECT_BITS: u32 = DIRECT_TOKENS.trailing_zeros();
+pub const ALPHABET_SIZE: usize = DIRECT_TOKENS + (u32::BITS - DIRECT_BITS) as usize;
+
+/*
+ * Raw bits are coded in chunks to keep rANS scales small.
+ */
+const RAW_CHUNK_BITS: u32 = 16;
+
+/*
+ * Precisions tried for explicitly transmitted histograms.
+ */
+pub const MIN_HISTOGRAM_BITS: u32 = 8;
+pub const MAX_HISTOGRAM_BITS: u32 = 12;
+
+/*
+ * Adaptive contexts count the tokens coded so far, starting from the Laplace prior
+ * of their bucket, and rebuild their table from the counts every rebuild period.
+ * Every token keeps a count of at least one so it stays codable, and counts are
+ * halved once their total would exceed the table precision, which keeps the table
+ * following local statistics.
+ */
+const ADAPTIVE_BITS: u32 = 16;
+const ADAPTIVE_PRIOR_TOTAL: f32 = 4096.;
+const ADAPTIVE_INCREMENT: u32 = 32;
+const ADAPTIVE_REBUILD_PERIOD: u32 = 32;
+
+/*
+ * Decoded coefficients are clamped to this magnitude. Coefficients of 8 bit images
+ * stay far below it, while corrupted streams cannot overflow the inverse transforms.
+ */
+pub const MAX_COEFFICIENT: i64 = 1 << 14;
+
+/*
+ * Largest probability scale accepted from the stream, rANS needs the scale to fit
+ * into 31 bits.
+ */
+pub const MAX_FREQ_BITS: u32 = 30;
+
+/*
+ * Decoders find the token of a slot by indexing a table with the top bits of the
+ * slot. Tables with at most this precision map every slot directly, with higher
+ * ones a few tokens of small frequency may share an entry and are scanned. Adaptive
+ * contexts rebuild their table often and get a coarser one.
+ */
+const LOOKUP_BITS: u32 = 12;
+const ADAPTIVE_LOOKUP_BITS: u32 = 10;
 
 //fn get_first_some_starting_from(i: usize, vec: &Vec<Option<i32>>) -> usize {
 //    (i..vec.len()).find(|j| vec[*j].is_some()).unwrap()
@@ -34,9 +88,17 @@ pub struct AnsContext {
     pub freqs: [u32; ALPHABET_SIZE],
     pub cdf: [u32; ALPHABET_SIZE],
     pub freqs_to_enc_symbols: Vec<B64RansEncSymbol>,
-    pub freqs_to_dec_symbols: HashMap<u32, B64RansDecSymbol>,
+    // First token of every slot prefix, see LOOKUP_BITS
+    pub slot_lookup: Vec<u16>,
     pub off_distribution_values: Vec<u16>,
     pub max_freq_bits: u32,
+    // Frequencies were transmitted instead of derived from the Laplace model
+    pub explicit_histogram: bool,
+    // Frequencies follow the coded symbols, see AdaptiveContext
+    pub adaptive: bool,
+    // Smallest estimated width coded in this context and width of its Laplace table
+    pub min_width: u32,
+    pub width: f32,
 }
 
 impl AnsContext {
@@ -46,12 +108,20 @@ impl AnsContext {
             cdf: [0; ALPHABET_SIZE],
             symbols: (0..ALPHABET_SIZE).map(|x| x as u32).collect(),
             freqs_to_enc_symbols: Vec::new(),
-            freqs_to_dec_symbols: HashMap::new(),
+            slot_lookup: Vec::new(),
             off_distribution_values: Vec::new(),
             max_freq_bits: 0,
+            explicit_histogram: false,
+            adaptive: false,
+            min_width: 0,
+            width: 1.,
         }
     }
 
+    pub fn for_bucket(min_width: u32, width: f32) -> Self {
+        AnsContext { min_width, width, ..AnsContext::new() }
+    }
+
     fn get_freqs(coefs: &Vec<u32>) -> [u32; ALPHABET_SIZE] {
         let mut freqs = [0; ALPHABET_SIZE];
         for coef in coefs {
@@ -65,7 +135,8 @@ impl AnsContext {
             .iter()
             .scan(0_u32, |acc, x| {
                 let val = *acc;
-                *acc += x;
+                // Narrow Laplace widths saturate single frequencies
+                *acc = acc.saturating_add(*x);
                 Some(val)
             })
             .collect::<Vec<u32>>()
@@ -79,10 +150,15 @@ impl AnsContext {
         }
     }
 
-    fn fill_with_laplace(&mut self, bucket: usize) {
-        let width = get_width_from_bucket(bucket);
+    fn fill_with_laplace(&mut self) {
+        let width = self.width;
         for (j, freq) in self.freqs.iter_mut().enumerate() {
-            let laplace_value = (laplace_distribution(utils::unpack_signed(j as u32) as f32, 0., width) * (1<<self.max_freq_bits) as f32) as u32;
+            // Escape tokens are rare enough to only ever be coded off distribution
+            let laplace_value = match j < DIRECT_TOKENS {
+                true => ((laplace_distribution(utils::unpack_signed(j as u32) as f32, 0., width) * (1<<self.max_freq_bits) as f32) as u32)
+                    .min(1 << self.max_freq_bits),
+                false => 0,
+            };
             if laplace_value == 0 && *freq == 0 && self.off_distribution_values.contains(&(j as u16)) {
                 *freq = 1;
             }
@@ -95,30 +171,172 @@ impl AnsContext {
         }
     }
 
+    /*
+     * Returns the token of a packed residual and the number of raw bits following it.
+     */
+    pub fn tokenize(value: u32) -> (u32, u32) {
+        if (value as usize) < DIRECT_TOKENS {
+            return (value, 0);
+        }
+        let extra_bits = u32::BITS - 1 - value.leading_zeros();
+        (DIRECT_TOKENS as u32 + extra_bits - DIRECT_BITS, extra_bits)
+    }
+
     pub fn bump_freq(&mut self, element: u32) {
-        self.freqs[element as usize] += 1;
+        self.freqs[Self::tokenize(element).0 as usize] += 1;
+    }
+
+    /*
+     * Pushes the symbols coding a packed residual in decoding order.
+     */
+    fn encode_value(&self, value: u32, lane: usize, symbols: &mut Vec<(B64RansEncSymbol, usize)>) {
+        let (token, extra_bits) = Self::tokenize(value);
+        symbols.push((self.freqs_to_enc_symbols[token as usize].clone(), lane));
+        for shift in (0..extra_bits).step_by(RAW_CHUNK_BITS as usize) {
+            let bits = RAW_CHUNK_BITS.min(extra_bits - shift);
+            let chunk = (value >> shift) & ((1 << bits) - 1);
+            symbols.push((B64RansEncSymbol::new(chunk, 1, bits), lane));
+        }
     }
 
-    pub fn finalize_context(&mut self, normalize: bool, bucket: usize) {
+    fn decode_value(&self, decoder: &mut RansDecoderMulti, lane: usize) -> u32 {
+        let token = self.find_token(decoder.get_at(lane, self.max_freq_bits));
+        decoder.advance_step_at(lane, self.cdf[token], self.freqs[token], self.max_freq_bits);
+        decoder.renorm_at(lane);
+
+        if token < DIRECT_TOKENS {
+            return token as u32;
+        }
+        let extra_bits = (token - DIRECT_TOKENS) as u32 + DIRECT_BITS;
+        let mut value = 1 << extra_bits;
+        for shift in (0..extra_bits).step_by(RAW_CHUNK_BITS as usize) {
+            let bits = RAW_CHUNK_BITS.min(extra_bits - shift);
+            let chunk = decoder.get_at(lane, bits);
+            decoder.advance_step_at(lane, chunk, 1, bits);
+            decoder.renorm_at(lane);
+            value |= chunk << shift;
+        }
+        value
+    }
+
+    /*
+     * Token whose range of slots contains the slot.
+     */
+    pub fn find_token(&self, slot: u32) -> usize {
+        let shift = self.max_freq_bits - self.slot_lookup.len().trailing_zeros();
+        let mut token = self.slot_lookup[(slot >> shift) as usize] as usize;
+        while token < ALPHABET_SIZE - 1 && slot >= self.cdf[token] + self.freqs[token] {
+            token += 1;
+        }
+        token
+    }
+
+    pub fn finalize_context(&mut self, normalize: bool) {
         if self.max_freq_bits < 8 {
             self.max_freq_bits = 8
         }
 
-        self.fill_with_laplace(bucket);
+        if !self.explicit_histogram && !self.adaptive {
+            self.fill_with_laplace();
+        }
         if normalize {
             self.cdf = self.normalize_freqs(1 << self.max_freq_bits);
         } else {
             self.cdf = self.get_cdf();
         }
-        self.max_freq_bits =
-            utils::get_prev_power_two(self.freqs.iter().sum::<u32>() as usize).trailing_zeros();
+        let total = self.freqs.iter().fold(0u32, |total, &freq| total.saturating_add(freq));
+        self.max_freq_bits = utils::get_prev_power_two(total as usize).trailing_zeros();
         self.freqs_to_enc_symbols = self.get_freqs_to_enc_symbols();
-        self.freqs_to_dec_symbols = self.get_freqs_to_dec_symbols();
+        self.slot_lookup = self.get_slot_lookup(LOOKUP_BITS);
+    }
+
+    /*
+     * Table an adaptive context of the bucket starts with, nothing about it is stored
+     * in the stream.
+     */
+    pub fn adaptive_prior(min_width: u32, width: f32) -> Self {
+        AdaptiveContext::new(min_width, width, false).table
+    }
+
+    /*
+     * Finalizes the context with the Laplace table of its bucket or with the measured
+     * histogram quantized to one of the supported precisions, whichever is estimated
+     * to code the symbols together with the context header in fewer bits.
+     */
+    pub fn finalize_cheapest(&mut self) {
+        let counts = self.freqs;
+        let mut best = self.clone();
+        best.finalize_context(true);
+        let mut best_cost = best.estimate_cost(&counts);
+
+        if counts.iter().any(|&count| count != 0) {
+            for bits in MIN_HISTOGRAM_BITS..=MAX_HISTOGRAM_BITS {
+                let mut candidate = self.clone();
+                candidate.explicit_histogram = true;
+                candidate.off_distribution_values.clear();
+                candidate.max_freq_bits = bits;
+                candidate.normalize_freqs(1 << bits);
+                candidate.finalize_context(true);
+
+                let cost = candidate.estimate_cost(&counts);
+                if cost < best_cost {
+                    best = candidate;
+                    best_cost = cost;
+                }
+            }
+        }
+        *self = best;
+    }
+
+    /*
+     * Bits needed for the context header and for coding the given token counts,
+     * raw bits of escaped residuals cost the same under any distribution.
+     */
+    fn estimate_cost(&self, counts: &[u32; ALPHABET_SIZE]) -> f64 {
+        let header_bytes = match self.explicit_histogram {
+            true => self.histogram_bytes().len(),
+            false => 12 + 2 * self.off_distribution_values.len(),
+        };
+        let mut cost = 8. * header_bytes as f64;
+        for (&count, &freq) in counts.iter().zip(self.freqs.iter()) {
+            if count == 0 {
+                continue;
+            }
+            if freq == 0 {
+                return f64::INFINITY;
+            }
+            cost += count as f64 * (self.max_freq_bits as f64 - (freq as f64).log2());
+        }
+        cost
+    }
+
+    /*
+     * Histograms are stored as their precision, the number of tokens up to the last
+     * one with a non zero frequency and the LEB128 frequencies of those tokens. Runs
+     * of zero frequencies are stored as a zero followed by the run length minus one.
+     */
+    pub fn histogram_bytes(&self) -> Vec<u8> {
+        let used = self.freqs.iter().rposition(|&freq| freq != 0).map_or(0, |last| last + 1);
+        let mut bytes = vec![self.max_freq_bits as u8];
+        utils::write_leb128(used as u64, &mut bytes);
+
+        let mut token = 0;
+        while token < used {
+            let freq = self.freqs[token];
+            utils::write_leb128(freq as u64, &mut bytes);
+            token += 1;
+            if freq == 0 {
+                let run = self.freqs[token..used].iter().take_while(|&&freq| freq == 0).count();
+                utils::write_leb128(run as u64, &mut bytes);
+                token += run;
+            }
+        }
+        bytes
     }
 
     pub fn normalize_freqs(&mut self, target_total: u32) -> [u32; ALPHABET_SIZE] {
         let mut cum_freqs = self.get_cdf();
-        let cur_total = *cum_freqs.last().unwrap() + self.freqs.last().unwrap();
+        let cur_total = cum_freqs.last().unwrap().saturating_add(*self.freqs.last().unwrap()).max(1);
         for i in 1..cum_freqs.len() {
             cum_freqs[i] = ((target_total as u64 * cum_freqs[i] as u64) / cur_total as u64) as u32;
         }
@@ -154,7 +372,7 @@ impl AnsContext {
         for i in 0..(cum_freqs.len() - 1) {
             self.freqs[i] = cum_freqs[i + 1] - cum_freqs[i];
         }
-        self.freqs[self.freqs.len() - 1] = cum_freqs[self.freqs.len() - 1] - target_total;
+        self.freqs[self.freqs.len() - 1] = target_total - cum_freqs[self.freqs.len() - 1];
         cum_freqs
     }
 
@@ -166,101 +384,346 @@ impl AnsContext {
             .collect()
     }
 
-    fn get_freqs_to_dec_symbols(&self) -> HashMap<u32, B64RansDecSymbol> {
-        self.cdf
-            .iter()
-            .zip(self.freqs.iter())
-            .map(|(&cum_freqs, &freqs)| (cum_freqs, B64RansDecSymbol::new(cum_freqs, freqs)))
+    /*
+     * Entry i holds the token whose range contains the first slot with prefix i.
+     */
+    fn get_slot_lookup(&self, bits: u32) -> Vec<u16> {
+        let shift = self.max_freq_bits.saturating_sub(bits);
+        let mut token = 0;
+        (0..1u32 << self.max_freq_bits.min(bits))
+            .map(|prefix| {
+                let slot = prefix << shift;
+                while token < ALPHABET_SIZE - 1 && slot >= self.cdf[token] + self.freqs[token] {
+                    token += 1;
+                }
+                token as u16
+            })
             .collect()
     }
 }
 
-// TODO: Implement Alias sampling method
-#[must_use]
-fn find_nearest_or_equal(cum_freq: u32, cum_freqs: &[u32]) -> u32 {
-    match cum_freqs.binary_search(&cum_freq) {
-        Ok(x) => cum_freqs[x],
-        Err(x) => cum_freqs[x - 1],
+pub struct AdaptiveContext {
+    table: AnsContext,
+    counts: Vec<u32>,
+    total: u32,
+    until_rebuild: u32,
+    // Rebuilds only make the tables needed in this direction
+    decoding: bool,
+}
+
+impl AdaptiveContext {
+    fn new(min_width: u32, width: f32, decoding: bool) -> Self {
+        let counts: Vec<u32> = (0..ALPHABET_SIZE)
+            .map(|token| match token < DIRECT_TOKENS {
+                // Clamped, so that narrow widths cannot overflow the total
+                true => ((laplace_distribution(utils::unpack_signed(token as u32) as f32, 0., width) * ADAPTIVE_PRIOR_TOTAL) as u32)
+                    .clamp(1, ADAPTIVE_PRIOR_TOTAL as u32),
+                false => 1,
+            })
+            .collect();
+        let mut context = AdaptiveContext {
+            table: AnsContext { adaptive: true, max_freq_bits: ADAPTIVE_BITS, ..AnsContext::for_bucket(min_width, width) },
+            total: counts.iter().sum(),
+            counts,
+            until_rebuild: 0,
+            decoding,
+        };
+        context.rebuild();
+        context
+    }
+
+    /*
+     * Fresh adaptive contexts for coding a group, none if the contexts are static.
+     * Groups are coded independently, so each one starts from the priors.
+     */
+    fn for_group(contexts: &[AnsContext], decoding: bool) -> Vec<AdaptiveContext> {
+        match contexts.iter().any(|context| context.adaptive) {
+            true => contexts.iter().map(|ctx| AdaptiveContext::new(ctx.min_width, ctx.width, decoding)).collect(),
+            false => vec![],
+        }
+    }
+
+    fn rebuild(&mut self) {
+        let table = &mut self.table;
+        table.freqs.copy_from_slice(&self.counts);
+        table.cdf = table.normalize_freqs(1 << ADAPTIVE_BITS);
+        match self.decoding {
+            true => table.slot_lookup = table.get_slot_lookup(ADAPTIVE_LOOKUP_BITS),
+            false => table.freqs_to_enc_symbols = table.get_freqs_to_enc_symbols(),
+        }
+        self.until_rebuild = ADAPTIVE_REBUILD_PERIOD;
+    }
+
+    fn update(&mut self, value: u32) {
+        if self.total + ADAPTIVE_INCREMENT > 1 << ADAPTIVE_BITS {
+            for count in self.counts.iter_mut() {
+                *count = (*count + 1) / 2;
+            }
+            self.total = self.counts.iter().sum();
+        }
+        self.counts[AnsContext::tokenize(value).0 as usize] += ADAPTIVE_INCREMENT;
+        self.total += ADAPTIVE_INCREMENT;
+
+        self.until_rebuild -= 1;
+        if self.until_rebuild == 0 {
+            self.rebuild();
+        }
     }
 }
 
-pub fn encode_symbol(
-    value: i32,
-    predicted_value: i32,
+const RANS64_L: u64 = 1 << 31;
+
+/*
+ * Interleaved 64 bit rANS decoder reading the streams of B64RansEncoderMulti.
+ * The C decoder trusts its input and reads past the buffer or aborts on corrupted
+ * states, this one treats missing data as zeros so any stream decodes to some image.
+ */
+struct RansDecoderMulti {
+    states: Vec<u64>,
+    data: Vec<u8>,
     position: usize,
-    width: usize,
-    channel: usize,
-    ans_contexts: &Vec<AnsContext>,
-) -> (B64RansEncSymbol, usize) {
-    let bucket = width;
-    let current_context = &ans_contexts[bucket];
-    let symbol_map = &current_context.freqs_to_enc_symbols;
-
-    (
-        symbol_map[utils::pack_signed(value - predicted_value) as usize].clone(),
-        bucket,
-    )
 }
 
-fn decode_symbol<const T: usize>(
+impl RansDecoderMulti {
+    fn new(data: Vec<u8>, lanes: usize) -> Self {
+        let mut decoder = RansDecoderMulti { states: vec![0; lanes], data, position: 0 };
+        for i in 0..lanes {
+            let low = decoder.read_word() as u64;
+            let high = decoder.read_word() as u64;
+            decoder.states[i] = low | (high << 32);
+        }
+        decoder
+    }
+
+    fn read_word(&mut self) -> u32 {
+        let mut word = [0; 4];
+        for (i, byte) in word.iter_mut().enumerate() {
+            *byte = self.data.get(self.position + i).copied().unwrap_or(0);
+        }
+        self.position += 4;
+        u32::from_le_bytes(word)
+    }
+
+    #[inline]
+    fn get_at(&self, channel: usize, scale_bits: u32) -> u32 {
+        (self.states[channel] & ((1 << scale_bits) - 1)) as u32
+    }
+
+    #[inline]
+    fn advance_step_at(&mut self, channel: usize, cum_freq: u32, freq: u32, scale_bits: u32) {
+        let x = self.states[channel];
+        let mask = (1 << scale_bits) - 1;
+        self.states[channel] = (freq as u64)
+            .wrapping_mul(x >> scale_bits)
+            .wrapping_add(x & mask)
+            .wrapping_sub(cum_freq as u64);
+    }
+
+    #[inline]
+    fn renorm_at(&mut self, channel: usize) {
+        if self.states[channel] < RANS64_L {
+            self.states[channel] = (self.states[channel] << 32) | self.read_word() as u64;
+        }
+    }
+}
+
+/*
+ * Codes the packed residuals of one fractal group, each with the context of its bucket.
+ */
+pub trait ResidualEncoder {
+    fn encode(&mut self, bucket: usize, residual: u32);
+    fn finish(self) -> Vec<u8>;
+}
+
+pub trait ResidualDecoder {
+    fn decode(&mut self, bucket: usize) -> u32;
+}
+
+/*
+ * Entropy coder of the residual streams. Contexts carry the coded distributions
+ * for rANS, other backends may only use their Laplace widths.
+ */
+pub trait EntropyBackend {
+    type Encoder<'a>: ResidualEncoder;
+    type Decoder<'a>: ResidualDecoder;
+
+    fn encoder(contexts: &[AnsContext]) -> Self::Encoder<'_>;
+    fn decoder(contexts: &[AnsContext], data: Vec<u8>) -> Self::Decoder<'_>;
+}
+
+pub struct RansBackend;
+
+pub struct RansEncoder<'a> {
+    contexts: &'a [AnsContext],
+    adaptive: Vec<AdaptiveContext>,
+    symbols: Vec<(B64RansEncSymbol, usize)>,
+}
+
+pub struct RansDecoder<'a> {
+    contexts: &'a [AnsContext],
+    adaptive: Vec<AdaptiveContext>,
+    decoder: RansDecoderMulti,
+}
+
+impl ResidualEncoder for RansEncoder<'_> {
+    fn encode(&mut self, bucket: usize, residual: u32) {
+        match self.adaptive.get_mut(bucket) {
+            Some(context) => {
+                context.table.encode_value(residual, bucket, &mut self.symbols);
+                context.update(residual);
+            }
+            None => self.contexts[bucket].encode_value(residual, bucket, &mut self.symbols),
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        encode_lanes(self.symbols, self.contexts.len())
+    }
+}
+
+impl ResidualDecoder for RansDecoder<'_> {
+    fn decode(&mut self, bucket: usize) -> u32 {
+        let lane = self.contexts.len() - bucket - 1;
+        match self.adaptive.get_mut(bucket) {
+            Some(context) => {
+                let symbol = context.table.decode_value(&mut self.decoder, lane);
+                context.update(symbol);
+                symbol
+            }
+            None => self.contexts[bucket].decode_value(&mut self.decoder, lane),
+        }
+    }
+}
+
+impl EntropyBackend for RansBackend {
+    type Encoder<'a> = RansEncoder<'a>;
+    type Decoder<'a> = RansDecoder<'a>;
+
+    fn encoder(contexts: &[AnsContext]) -> RansEncoder<'_> {
+        RansEncoder { contexts, adaptive: AdaptiveContext::for_group(contexts, false), symbols: vec![] }
+    }
+
+    fn decoder(contexts: &[AnsContext], data: Vec<u8>) -> RansDecoder<'_> {
+        RansDecoder {
+            contexts,
+            adaptive: AdaptiveContext::for_group(contexts, true),
+            decoder: RansDecoderMulti::new(data, contexts.len()),
+        }
+    }
+}
+
+/*
+ * Coefficients of one channel being decoded, keyed by fractal center.
+ */
+type ChannelCoefficients = HashMap<Complex<i32>, Vec<Option<i32>>>;
+
+fn decode_symbol<D: ResidualDecoder>(
     image_position: Complex<i32>,
     haar_tree_position: usize,
     depth: u8,
     parent_pos: &Complex<i32>,
-    channel: usize,
     ans_contexts: &Vec<AnsContext>,
     fractal_lattice: &HashMap<Complex<i32>, Fractal>,
+    coefficients: &ChannelCoefficients,
     global_position_map: &Vec<HashMap<Complex<i32>, Complex<i32>>>,
     value_prediction_params: &Vec<[f32; 6]>,
     width_prediction_params: &Vec<[f32; 6]>,
-    decoder: &mut B64RansDecoderMulti<T>,
+    decoder: &mut D,
 ) -> i32 {
-    let (bucket, prediction) = if depth == 0 {
-        prediction::get_lf_context_bucket(
+    let (width, prediction) = if depth == 0 {
+        prediction::get_lf_context(
             haar_tree_position,
             depth,
             parent_pos,
             &fractal_lattice,
-            channel,
+            coefficients,
         )
     } else {
-        prediction::get_hf_context_bucket(
+        prediction::get_hf_context(
             image_position,
             depth,
             parent_pos,
             &fractal_lattice,
+            coefficients,
             &global_position_map,
             value_prediction_params,
             &width_prediction_params,
-            channel,
         )
     };
 
-    let decoder_pos = CONTEXT_AMOUNT - bucket - 1;
-    let current_context = &ans_contexts[bucket];
-    let cum_freq_to_symbols = &current_context.freqs_to_dec_symbols;
+    let symbol = decoder.decode(assign_bucket(width, ans_contexts));
+    (utils::unpack_signed(symbol) as i64 + prediction as i64).clamp(-MAX_COEFFICIENT, MAX_COEFFICIENT) as i32
+}
+
+fn encode_group<B: EntropyBackend>(
+    image: &WaveletImage,
+    group_lattice: &[Vec<Complex<i32>>],
+    global_depth: u8,
+    channel: usize,
+    contexts: &Vec<AnsContext>,
+) -> Vec<u8> {
+    let mut encoder = B::encoder(contexts);
+
+    // First scan -> Low frequency coefficients
+    for (i, image_pos) in group_lattice[0].iter().enumerate() {
+        let fractal = &image.fractal_lattice.get(image_pos).unwrap();
+        if let Some(value) = fractal.coefficients[channel][0] {
+            let (bucket, prediction) = fractal.parameter_predictors[channel][0];
+            encoder.encode(bucket, utils::pack_signed(value.wrapping_sub(prediction)));
+        }
+    }
+
+    // Second scan -> High frequency coefficient root
+    for (i, image_pos) in group_lattice[0].iter().enumerate() {
+        let fractal = &image.fractal_lattice.get(image_pos).unwrap();
+        if let Some(value) = fractal.coefficients[channel][1] {
+            let (bucket, prediction) = fractal.parameter_predictors[channel][1];
+            encoder.encode(bucket, utils::pack_signed(value.wrapping_sub(prediction)));
+        }
+    }
 
-    let decoded_cdf = decoder.get_at(decoder_pos, current_context.max_freq_bits);
-    let cum_freq_decoded = find_nearest_or_equal(decoded_cdf, &current_context.cdf);
+    // Remaining levels
+    for level in (1..global_depth) {
+        for (i, image_pos) in group_lattice[level as usize].iter().enumerate() {
+            let parent_pos = &image.global_position_map[level as usize][&image_pos];
+            let fractal = &image.fractal_lattice.get(parent_pos).unwrap();
+            let haar_tree_pos = fractal.position_map[level as usize]
+                .get(&image_pos)
+                .unwrap();
+            if let Some(value) = fractal.coefficients[channel][*haar_tree_pos] {
+                let (bucket, prediction) = fractal.parameter_predictors[channel][*haar_tree_pos];
+                encoder.encode(bucket, utils::pack_signed(value.wrapping_sub(prediction)));
+            }
+        }
+    }
 
-    let mut symbol = current_context
-        .cdf
-        .iter()
-        .position(|&r| r == cum_freq_decoded)
-        .unwrap() as u32;
+    encoder.finish()
+}
 
-    while current_context.cdf[symbol as usize] == cum_freq_decoded {
-        symbol += 1;
+/*
+ * Interleaves symbols into one rANS lane per context. The lane count is a type
+ * parameter of the encoder, so every supported count gets an instance of its own.
+ */
+fn encode_lanes(symbols: Vec<(B64RansEncSymbol, usize)>, lanes: usize) -> Vec<u8> {
+    fn encode<const N: usize>(symbols: Vec<(B64RansEncSymbol, usize)>) -> Vec<u8> {
+        // Every symbol emits at most one word, on top of the final states
+        let mut encoder: B64RansEncoderMulti<N> = B64RansEncoderMulti::new(4 * (symbols.len() + 2 * N));
+        for (symbol, lane) in symbols.into_iter().rev() {
+            encoder.put_at(lane, &symbol);
+        }
+        encoder.flush_all();
+        encoder.data().to_owned()
     }
-    symbol -= 1;
 
-    decoder.advance_step_at(
-        decoder_pos,
-        &cum_freq_to_symbols[&cum_freq_decoded],
-        current_context.max_freq_bits,
-    );
-    decoder.renorm_at(decoder_pos);
-    return utils::unpack_signed(symbol) + prediction;
+    macro_rules! encode_with {
+        ($($n:literal)*) => {
+            match lanes {
+                $($n => encode::<$n>(symbols),)*
+                _ => unreachable!("contexts are limited to MAX_CONTEXTS"),
+            }
+        };
+    }
+    encode_with!(1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16)
 }
 
 pub fn encode(
@@ -272,75 +735,44 @@ pub fn encode(
 
     //dbg!(&contexts[0][0].freqs_to_enc_symbols);
     let sorted_lattice = image.get_sorted_lattice();
+    let group_lattices = image.get_group_lattices();
 
     let global_depth = image.fractal_lattice[&sorted_lattice[0][0]].depth;
-    for channel in 0..image.metadata.colorspace.num_channels() {
-        let mut encoder: B64RansEncoderMulti<CONTEXT_AMOUNT> =
-            B64RansEncoderMulti::new(image.fractal_lattice.len() * 2 * (1 << global_depth));
-
-        let mut enc_symbols = Vec::<(B64RansEncSymbol, usize)>::new();
-        enc_symbols.reserve(1 << global_depth);
-
-        // First scan -> Low frequency coefficients
-        for (i, image_pos) in sorted_lattice[0].iter().enumerate() {
-            let fractal = &image.fractal_lattice.get(image_pos).unwrap();
-            let haar_tree_pos = fractal.position_map[0 as usize].get(&image_pos).unwrap();
-            if let Some(value) = fractal.coefficients[channel][0] {
-                let (width, prediction) = fractal.parameter_predictors[channel][0];
-                let symbol =
-                    encode_symbol(value, prediction, 0, width, channel, &contexts[channel]);
-                enc_symbols.push(symbol);
-            }
-        }
+    // Every fractal group is an independent stream, so the decoder can skip it and
+    // the encoder can code all groups of all channels at the same time
+    let channel_streams: Vec<Vec<Vec<u8>>> = (0..image.metadata.colorspace.num_channels())
+        .into_par_iter()
+        .map(|channel| {
+            group_lattices
+                .par_iter()
+                .map(|group_lattice| match encoder_opts.entropy_coder {
+                    EntropyCoder::Rans => {
+                        encode_group::<RansBackend>(&image, group_lattice, global_depth, channel, &contexts[channel])
+                    }
+                    EntropyCoder::Binary => {
+                        encode_group::<BinaryBackend>(&image, group_lattice, global_depth, channel, &contexts[channel])
+                    }
+                })
+                .collect()
+        })
+        .collect();
 
-        // Second scan -> High frequency coefficient root
-        for (i, image_pos) in sorted_lattice[0].iter().enumerate() {
-            let fractal = &image.fractal_lattice.get(image_pos).unwrap();
-            let haar_tree_pos = fractal.position_map[0 as usize].get(&image_pos).unwrap();
-            if let Some(value) = fractal.coefficients[channel][1] {
-                let (width, prediction) = fractal.parameter_predictors[channel][1];
-                let symbol =
-                    encode_symbol(value, prediction, 1, width, channel, &contexts[channel]);
-                enc_symbols.push(symbol);
-            }
+    for (channel, streams) in channel_streams.into_iter().enumerate() {
+        let mut data = vec![];
+        let mut group_offsets = vec![];
+        for stream in streams {
+            group_offsets.push(data.len());
+            data.extend(stream);
         }
 
-        // Remaining levels
-        for level in (1..global_depth) {
-            for (i, image_pos) in sorted_lattice[level as usize].iter().enumerate() {
-                let parent_pos = &image.global_position_map[level as usize][&image_pos];
-                let fractal = &image.fractal_lattice.get(parent_pos).unwrap();
-                let haar_tree_pos = fractal.position_map[level as usize]
-                    .get(&image_pos)
-                    .unwrap();
-                if let Some(value) = fractal.coefficients[channel][*haar_tree_pos] {
-                    let (width, prediction) = fractal.parameter_predictors[channel][*haar_tree_pos];
-                    let symbol = encode_symbol(
-                        value,
-                        prediction,
-                        *haar_tree_pos,
-                        width,
-                        channel,
-                        &contexts[channel],
-                    );
-                    enc_symbols.push(symbol);
-                }
-            }
-        }
-
-        let len1 = enc_symbols.len();
-        for (symbol, bucket) in enc_symbols.into_iter().rev() {
-            encoder.put_at(bucket, &symbol);
-        }
-        encoder.flush_all();
-        let data = encoder.data().to_owned();
-        let bpp = data.len() as f32 / (image.metadata.width * image.metadata.height) as f32 * 8.;
+        let bpp = data.len() as f32 / (image.metadata.width as f32 * image.metadata.height as f32) * 8.;
         if encoder_opts.verbose {
             println!("bits per pixel: {}", bpp);
         }
         channel_data[channel] = Some(ChannelData {
             ans_contexts: contexts[channel].clone(),
             data,
+            group_offsets,
             value_prediction_parameters: encoder_opts.value_prediction_params[channel].clone(),
             width_prediction_parameters: encoder_opts.width_prediction_params[channel].clone(),
         });
@@ -348,100 +780,228 @@ pub fn encode(
     Ok(CompressedImage {
         metadata: image.metadata,
         channel_data,
+        quantization_matrix: image.quantization_matrix,
+        group_size: image.group_size,
+        entropy_coder: encoder_opts.entropy_coder,
     })
 }
 
-pub fn decode(mut compressed_image: CompressedImage) -> Result<WaveletImage, String> {
-    let mut decoded = WaveletImage::from_metadata(compressed_image.metadata);
+fn decode_group<B: EntropyBackend>(
+    fractal_lattice: &HashMap<Complex<i32>, Fractal>,
+    coefficients: &mut ChannelCoefficients,
+    global_position_map: &Vec<HashMap<Complex<i32>, Complex<i32>>>,
+    group_lattice: &[Vec<Complex<i32>>],
+    last_level: u8,
+    data: Vec<u8>,
+    ans_contexts: &Vec<AnsContext>,
+    value_prediction_parameters: &Vec<[f32; 6]>,
+    width_prediction_parameters: &Vec<[f32; 6]>,
+) {
+    let mut decoder = B::decoder(ans_contexts, data);
+    // First scan -> Low frequency coefficients
+    for (i, image_pos) in group_lattice[0].iter().enumerate() {
+        let symbol = decode_symbol(
+            *image_pos,
+            0,
+            0,
+            image_pos,
+            ans_contexts,
+            fractal_lattice,
+            coefficients,
+            global_position_map,
+            value_prediction_parameters,
+            width_prediction_parameters,
+            &mut decoder,
+        );
+        coefficients.get_mut(image_pos).unwrap()[0] = Some(symbol);
+    }
+
+    // Second scan -> High frequency coefficient root
+    for (i, image_pos) in group_lattice[0].iter().enumerate() {
+        let symbol = decode_symbol(
+            *image_pos,
+            1,
+            0,
+            image_pos,
+            ans_contexts,
+            fractal_lattice,
+            coefficients,
+            global_position_map,
+            value_prediction_parameters,
+            width_prediction_parameters,
+            &mut decoder,
+        );
+        coefficients.get_mut(image_pos).unwrap()[1] = Some(symbol);
+    }
 
-    let sorted_lattice = decoded.get_sorted_lattice().clone();
-    let mut channel = 0;
-    let global_depth = decoded.fractal_lattice[&sorted_lattice[0][0]].depth;
-    while let Some(ChannelData {
-        ans_contexts,
-        data,
-        value_prediction_parameters,
-        width_prediction_parameters,
-    }) = compressed_image.channel_data[channel].take()
-    {
-        let mut decoder: B64RansDecoderMulti<CONTEXT_AMOUNT> = B64RansDecoderMulti::new(data);
-        // First scan -> Low frequency coefficients
-        for (i, image_pos) in sorted_lattice[0].iter().enumerate() {
-            let fractal = &decoded.fractal_lattice.get(image_pos).unwrap();
-            let haar_tree_pos = fractal.position_map[0 as usize].get(&image_pos).unwrap();
+    // Remaining levels
+    for level in (1..last_level) {
+        for (i, image_pos) in group_lattice[level as usize].iter().enumerate() {
+            let parent_pos = &global_position_map[level as usize][&image_pos];
+            let fractal = &fractal_lattice.get(parent_pos).unwrap();
+            let haar_tree_pos = fractal.position_map[level as usize]
+                .get(&image_pos)
+                .unwrap()
+                .clone();
+            if coefficients[parent_pos][haar_tree_pos].is_none() {
+                continue;
+            }
             let symbol = decode_symbol(
                 *image_pos,
-                0,
-                0,
-                image_pos,
-                channel,
-                &ans_contexts,
-                &decoded.fractal_lattice,
-                &decoded.global_position_map,
-                &value_prediction_parameters,
-                &width_prediction_parameters,
+                haar_tree_pos,
+                level,
+                parent_pos,
+                ans_contexts,
+                fractal_lattice,
+                coefficients,
+                global_position_map,
+                value_prediction_parameters,
+                width_prediction_parameters,
                 &mut decoder,
             );
-            let fractal = decoded.fractal_lattice.get_mut(&image_pos).unwrap();
-            fractal.coefficients[channel][0] = Some(symbol);
+
+            coefficients.get_mut(parent_pos).unwrap()[haar_tree_pos] = Some(symbol);
         }
+    }
+}
 
-        // Second scan -> High frequency coefficient root
-        for (i, image_pos) in sorted_lattice[0].iter().enumerate() {
-            let fractal = &decoded.fractal_lattice.get(image_pos).unwrap();
-            let haar_tree_pos = fractal.position_map[0 as usize].get(&image_pos).unwrap();
-            let symbol = decode_symbol(
-                *image_pos,
-                1,
-                0,
-                image_pos,
-                channel,
-                &ans_contexts,
-                &decoded.fractal_lattice,
-                &decoded.global_position_map,
-                &value_prediction_parameters,
-                &width_prediction_parameters,
-                &mut decoder,
-            );
-            let fractal = decoded.fractal_lattice.get_mut(&image_pos).unwrap();
-            fractal.coefficients[channel][1] = Some(symbol);
-        }
-
-        // Remaining levels
-        for level in (1..global_depth) {
-            for (i, image_pos) in sorted_lattice[level as usize].iter().enumerate() {
-                let parent_pos = &decoded.global_position_map[level as usize][&image_pos];
-                let fractal = &decoded.fractal_lattice.get(parent_pos).unwrap();
-                let haar_tree_pos = fractal.position_map[level as usize]
-                    .get(&image_pos)
-                    .unwrap()
-                    .clone();
-                if decoded.fractal_lattice[parent_pos].coefficients[channel][haar_tree_pos]
-                    .is_none()
-                {
-                    continue;
-                }
-                let symbol = decode_symbol(
-                    *image_pos,
-                    haar_tree_pos,
-                    level,
-                    parent_pos,
-                    channel,
-                    &ans_contexts,
-                    &decoded.fractal_lattice,
-                    &decoded.global_position_map,
-                    &value_prediction_parameters,
-                    &width_prediction_parameters,
-                    &mut decoder,
-                );
-
-                let fractal = decoded.fractal_lattice.get_mut(&parent_pos).unwrap();
-                fractal.coefficients[channel][haar_tree_pos] = Some(symbol);
+fn decode_channel(
+    fractal_lattice: &HashMap<Complex<i32>, Fractal>,
+    coefficients: &mut ChannelCoefficients,
+    global_position_map: &Vec<HashMap<Complex<i32>, Complex<i32>>>,
+    group_lattices: &[[Vec<Complex<i32>>; BASE_FRAC_DEPTH as usize]],
+    needed_groups: &[bool],
+    last_level: u8,
+    channel_data: ChannelData,
+    entropy_coder: EntropyCoder,
+) -> Result<(), FriError> {
+    let ChannelData {
+        ans_contexts,
+        data,
+        group_offsets,
+        value_prediction_parameters,
+        width_prediction_parameters,
+    } = channel_data;
+    if group_offsets.len() != group_lattices.len() {
+        return Err(FriError::MalformedSegment("GIX"));
+    }
+    if ans_contexts.is_empty() || ans_contexts.len() > MAX_CONTEXTS {
+        return Err(FriError::MalformedSegment("EHD"));
+    }
+
+    for (group, group_lattice) in group_lattices.iter().enumerate() {
+        if !needed_groups[group] {
+            continue;
+        }
+        let start = group_offsets[group];
+        let end = group_offsets.get(group + 1).cloned().unwrap_or(data.len());
+        if start > end || end > data.len() {
+            return Err(FriError::MalformedSegment("GIX"));
+        }
+        let decode = match entropy_coder {
+            EntropyCoder::Rans => decode_group::<RansBackend>,
+            EntropyCoder::Binary => decode_group::<BinaryBackend>,
+        };
+        decode(
+            fractal_lattice,
+            coefficients,
+            global_position_map,
+            group_lattice,
+            last_level,
+            data[start..end].to_vec(),
+            &ans_contexts,
+            &value_prediction_parameters,
+            &width_prediction_parameters,
+        );
+    }
+    Ok(())
+}
+
+pub fn decode(
+    compressed_image: CompressedImage,
+    decoder_opts: &DecoderOpts,
+) -> Result<WaveletImage, FriError> {
+    let lattice = WaveletImage::from_metadata(compressed_image.metadata.clone());
+    decode_with_lattice(lattice, compressed_image, decoder_opts)
+}
+
+/*
+ * Decodes into a lattice built beforehand for the same dimensions, which lets
+ * frames of a sequence share it. Its coefficients have to be zero.
+ */
+pub fn decode_with_lattice(
+    mut decoded: WaveletImage,
+    mut compressed_image: CompressedImage,
+    decoder_opts: &DecoderOpts,
+) -> Result<WaveletImage, FriError> {
+    // Every channel of the colorspace needs its stream, a stream ending early
+    // would otherwise decode to black channels
+    let channels: Vec<ChannelData> = compressed_image.channel_data.iter_mut().filter_map(Option::take).collect();
+    if channels.len() != compressed_image.metadata.colorspace.num_channels() {
+        return Err(FriError::MalformedSegment("EOC"));
+    }
+
+    decoded.metadata = compressed_image.metadata;
+    decoded.quantization_matrix = compressed_image.quantization_matrix;
+    decoded.assign_groups(compressed_image.group_size);
+
+    let sorted_lattice = decoded.get_sorted_lattice().clone();
+    let group_lattices = decoded.get_group_lattices();
+    let global_depth = match sorted_lattice[0].first() {
+        Some(position) => decoded.fractal_lattice[position].depth,
+        None => return Err(FriError::InvalidHeader("image is not covered by the fractal lattice")),
+    };
+
+    // Levels past max_level keep zero coefficients, which makes the wavelet
+    // transform fill every subtree with the low-pass value of its root
+    let last_level = decoder_opts
+        .max_level
+        .map_or(global_depth, |level| level.saturating_add(1).min(global_depth));
+
+    // Groups without any fractal intersecting the region are never decoded
+    let mut needed_groups = vec![decoder_opts.region.is_none(); decoded.num_groups];
+    if let Some(region) = &decoder_opts.region {
+        for fractal in decoded.fractal_lattice.values() {
+            if fractal.intersects(region) {
+                needed_groups[fractal.group] = true;
             }
         }
-        channel += 1;
-        if channel >= decoded.metadata.colorspace.num_channels() {
-            break;
+    }
+
+    // Channel streams are independent, every channel is decoded into its own
+    // coefficient buffer next to the shared lattice and moved back afterwards
+    let mut buffers: Vec<ChannelCoefficients> = (0..channels.len())
+        .map(|channel| {
+            decoded
+                .fractal_lattice
+                .iter_mut()
+                .map(|(position, fractal)| (*position, std::mem::take(&mut fractal.coefficients[channel])))
+                .collect()
+        })
+        .collect();
+    let fractal_lattice = &decoded.fractal_lattice;
+    let global_position_map = &decoded.global_position_map;
+    let entropy_coder = compressed_image.entropy_coder;
+    buffers
+        .par_iter_mut()
+        .zip(channels)
+        .map(|(coefficients, channel_data)| {
+            decode_channel(
+                fractal_lattice,
+                coefficients,
+                global_position_map,
+                &group_lattices,
+                &needed_groups,
+                last_level,
+                channel_data,
+                entropy_coder,
+            )
+        })
+        .collect::<Result<Vec<()>, FriError>>()?;
+
+    for (channel, coefficients) in buffers.into_iter().enumerate() {
+        for (position, channel_coefficients) in coefficients {
+            decoded.fractal_lattice.get_mut(&position).unwrap().coefficients[channel] = channel_coefficients;
         }
     }
 
@@ -461,4 +1021,65 @@ mod test {
 
     #[test]
     fn logic_test() {}
+
+    #[test]
+    fn histograms_are_chosen_for_non_laplacian_residuals() {
+        let mut context = AnsContext::new();
+        for value in [0, 40, 80] {
+            for _ in 0..1000 {
+                context.bump_freq(value);
+            }
+        }
+        let counts = context.freqs;
+        context.max_freq_bits = 11;
+
+        let mut laplace = context.clone();
+        laplace.finalize_context(true);
+        context.finalize_cheapest();
+
+        assert!(context.explicit_histogram);
+        assert!(context.estimate_cost(&counts) < laplace.estimate_cost(&counts));
+        assert_eq!(context.freqs.iter().sum::<u32>(), 1 << context.max_freq_bits);
+    }
+
+    #[test]
+    fn large_residuals_round_trip() {
+        let values = [0, 1, 7, 1023, 1024, 1025, 70_000, 1 << 31, u32::MAX, 3, 0];
+        let mut context = AnsContext::new();
+        for value in values {
+            context.bump_freq(value);
+        }
+        context.max_freq_bits = 12;
+        context.finalize_context(true);
+
+        let mut symbols = vec![];
+        for value in values {
+            context.encode_value(value, 0, &mut symbols);
+        }
+        let mut decoder = RansDecoderMulti::new(encode_lanes(symbols, 1), 1);
+        for value in values {
+            assert_eq!(context.decode_value(&mut decoder, 0), value);
+        }
+    }
+
+    #[test]
+    fn adaptive_coding_round_trip() {
+        use crate::encoder::{EncoderOpts, EncoderQuality};
+        use crate::test_images::{self, noisy_gradient};
+
+        let (width, height) = (37, 23);
+        let data = noisy_gradient(width, height, 63);
+
+        for (quality, tile_size) in [(EncoderQuality::Lossless, None), (EncoderQuality::Low, Some(16))] {
+            let round_trip = |adaptive| {
+                let opts = EncoderOpts { quality, tile_size, adaptive, ..Default::default() };
+                test_images::round_trip(opts, width, height, &data).data
+            };
+            let adaptive = round_trip(true);
+            assert_eq!(adaptive, round_trip(false));
+            if let EncoderQuality::Lossless = quality {
+                assert_eq!(adaptive, data);
+            }
+        }
+    }
 }
@@ -2,5 +2,7 @@ pub mod channel_transform;
 pub mod wavelet_transform;
 pub mod quantization;
 pub mod entropy_coding;
+pub mod binary_coding;
 pub mod serialize;
+pub mod temporal_prediction;
 pub mod prediction;
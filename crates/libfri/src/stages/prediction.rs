@@ -1,18 +1,45 @@
 use std::cmp::{max, min};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::io::Write;
 
 use num::pow::Pow;
 use num::{Complex, PrimInt};
+use rayon::prelude::*;
 
 use crate::context_modeling::ContextModeler;
 use crate::encoder::EncoderOpts;
-use crate::stages::entropy_coding::AnsContext;
+use crate::images::EntropyCoder;
+use crate::stages::entropy_coding::{AnsContext, ALPHABET_SIZE};
 use crate::stages::wavelet_transform::{Fractal, WaveletImage};
 use crate::{fractal, utils};
 
-pub const CONTEXT_AMOUNT: usize = 10;
+/*
+ * Residuals are coded in contexts picked by the estimated width of their
+ * distribution. Every context covers the widths from its own minimum up to the
+ * next one and starts from a Laplace distribution of its width. Streams without
+ * buckets of their own use these.
+ */
+pub const MAX_CONTEXTS: usize = 16;
+pub const DEFAULT_BUCKETS: [(u32, f32); 10] = [
+    (0, 2.5),
+    (3, 4.5),
+    (5, 6.3),
+    (6, 8.5),
+    (8, 12.7),
+    (12, 16.),
+    (16, 20.),
+    (20, 24.),
+    (25, 28.),
+    (30, 36.),
+];
+
+// Narrowest Laplace width of a context, the decoder rejects anything below it
+pub const MIN_CONTEXT_WIDTH: f32 = 0.5;
+// Widths from the last bin on share one bin when clustering
+const CLUSTER_BINS: usize = 128;
+// Rough cost of storing and learning the distribution of one more context
+const CONTEXT_COST_BITS: f64 = 1024.;
 
 fn emit_coefficients(data: &[u32], ctx_id: usize, ctx_channel: usize) {
     std::fs::create_dir_all("./coefficients").unwrap();
@@ -52,59 +79,121 @@ fn get_containing_fractal(
     None
 }
 
-pub fn assign_bucket(width: f32) -> usize {
-    match width as u32 {
-        0..3 => 0,
-        3..5 => 1,
-        5..6 => 2,
-        6..8 => 3,
-        8..12 => 4,
-        12..16 => 5,
-        16..20 => 6,
-        20..25 => 7,
-        25..30 => 8,
-        30.. => 9,
-    }
+pub fn assign_bucket(width: f32, contexts: &[AnsContext]) -> usize {
+    let width = width as u32;
+    contexts.partition_point(|ctx| ctx.min_width <= width).saturating_sub(1)
 }
 
-pub fn get_width_from_bucket(bucket: usize) -> f32 {
-    match bucket as u32 {
-        0 => 2.5,
-        1 => 4.5,
-        2 => 6.3,
-        3 => 8.5,
-        4 => 12.7,
-        5 => 16.,
-        6 => 20.,
-        7 => 24.,
-        8 => 28.,
-        9 => 36.,
-        10.. => 50.
+/*
+ * Splits estimated widths into at most MAX_CONTEXTS buckets, or exactly `count` of
+ * them when given, minimizing the entropy of the residuals falling into each bucket
+ * plus a fixed cost per context. Widths are binned by their integer part and runs
+ * of bins are merged by dynamic programming. Every bucket gets the Laplace width
+ * fitted to its residuals. Samples are pairs of width and packed residual.
+ */
+pub fn cluster_buckets(samples: &[(f32, u32)], count: Option<usize>) -> Vec<(u32, f32)> {
+    // Ordered histograms keep the floating point sums below independent of hashing
+    let mut histograms = vec![BTreeMap::<u32, u32>::new(); CLUSTER_BINS];
+    let mut magnitudes = vec![0f64; CLUSTER_BINS];
+    for &(width, residual) in samples {
+        let bin = (width as usize).min(CLUSTER_BINS - 1);
+        *histograms[bin].entry(AnsContext::tokenize(residual).0).or_default() += 1;
+        magnitudes[bin] += utils::unpack_signed(residual).unsigned_abs() as f64;
+    }
+    let bins: Vec<usize> = (0..CLUSTER_BINS).filter(|&bin| !histograms[bin].is_empty()).collect();
+    if bins.is_empty() {
+        return DEFAULT_BUCKETS.to_vec();
+    }
+
+    // Entropy in bits of every run of bins, kept as n log n sums while extending the run
+    let m = bins.len();
+    let n_log_n = |n: u32| if n == 0 { 0. } else { n as f64 * (n as f64).log2() };
+    let mut run_cost = vec![vec![0f64; m]; m];
+    for start in 0..m {
+        let mut counts = vec![0u32; ALPHABET_SIZE];
+        let (mut total, mut sum) = (0u32, 0f64);
+        for end in start..m {
+            for (&token, &count) in &histograms[bins[end]] {
+                let counter = &mut counts[token as usize];
+                sum += n_log_n(*counter + count) - n_log_n(*counter);
+                *counter += count;
+                total += count;
+            }
+            run_cost[start][end] = n_log_n(total) - sum;
+        }
+    }
+
+    // best[k][end] holds the cost of the first `end` bins split into k + 1 buckets
+    let max_count = count.unwrap_or(MAX_CONTEXTS).clamp(1, MAX_CONTEXTS).min(m);
+    let mut best = vec![vec![f64::INFINITY; m + 1]; max_count];
+    let mut split = vec![vec![0; m + 1]; max_count];
+    for end in 1..=m {
+        best[0][end] = run_cost[0][end - 1];
+    }
+    for k in 1..max_count {
+        for end in k + 1..=m {
+            for start in k..end {
+                let cost = best[k - 1][start] + run_cost[start][end - 1];
+                if cost < best[k][end] {
+                    best[k][end] = cost;
+                    split[k][end] = start;
+                }
+            }
+        }
+    }
+
+    let buckets = match count {
+        Some(_) => max_count,
+        None => (1..=max_count)
+            .min_by(|&a, &b| {
+                let cost = |k: usize| best[k - 1][m] + k as f64 * CONTEXT_COST_BITS;
+                cost(a).total_cmp(&cost(b))
+            })
+            .unwrap(),
+    };
+
+    let mut bounds = vec![m];
+    for k in (1..buckets).rev() {
+        bounds.push(split[k][*bounds.last().unwrap()]);
     }
+    bounds.push(0);
+    bounds.reverse();
+
+    bounds
+        .windows(2)
+        .enumerate()
+        .map(|(i, run)| {
+            let run_bins = &bins[run[0]..run[1]];
+            let count: u32 = run_bins.iter().map(|&bin| histograms[bin].values().sum::<u32>()).sum();
+            let magnitude: f64 = run_bins.iter().map(|&bin| magnitudes[bin]).sum();
+            let min_width = if i == 0 { 0 } else { run_bins[0] as u32 };
+            (min_width, ((magnitude / count as f64) as f32).max(MIN_CONTEXT_WIDTH))
+        })
+        .collect()
 }
 
-pub fn get_lf_context_bucket(
+pub fn get_lf_context<C: AsRef<[Option<i32>]>>(
     position: usize,
     current_depth: u8,
     parent_fractal_pos: &Complex<i32>,
     fractal_lattice: &HashMap<Complex<i32>, Fractal>,
-    channel: usize,
-) -> (usize, i32) {
+    coefficients: &HashMap<Complex<i32>, C>,
+) -> (f32, i32) {
     let fractal = &fractal_lattice[parent_fractal_pos];
     let position_in_image = fractal.image_positions[position];
     let global_pos = vec![];
     let neighbours = vec![
-        Fractal::get_left(
+        fractal.get_left(
             position_in_image,
             fractal.depth - current_depth,
             &global_pos,
         ),
-        Fractal::get_up_left(
+        fractal.get_up_left(
             position_in_image,
             fractal.depth - current_depth,
             &global_pos,
         ),
-        Fractal::get_up_right(
+        fractal.get_up_right(
             position_in_image,
             fractal.depth - current_depth,
             &global_pos,
@@ -120,20 +209,21 @@ pub fn get_lf_context_bucket(
                     get_containing_fractal(pos, level, fractal, fractal_lattice)
                 {
                     let containing_fractal = &fractal_lattice[&nposition];
-                    containing_fractal.coefficients[channel][position].unwrap_or(0)
+                    if containing_fractal.group != fractal.group {
+                        return 0;
+                    }
+                    coefficients[&nposition].as_ref()[position].unwrap_or(0)
                 } else {
                     0
                 }
             } else {
                 let loc = fractal.position_map[level][pos];
-                fractal.coefficients[channel][loc].unwrap_or(0)
+                coefficients[parent_fractal_pos].as_ref()[loc].unwrap_or(0)
             }
         })
         .collect();
 
-    let width: u32 = (values[0] - values[2]).abs() as u32;
-
-    let bucket = assign_bucket(width as f32);
+    let width = (values[0] - values[2]).unsigned_abs() as f32;
 
     let prediction = if values[1] >= max(values[0], values[2]) {
         max(values[0], values[2])
@@ -142,22 +232,19 @@ pub fn get_lf_context_bucket(
     } else {
         values[0] + values[2] - values[1]
     };
-    //let bucket = 0;
-    //let prediction = 0;
-
-    (bucket, prediction as i32)
+    (width, prediction as i32)
 }
 
-pub fn get_hf_context_bucket(
+pub fn get_hf_context<C: AsRef<[Option<i32>]>>(
     image_position: Complex<i32>,
     current_depth: u8,
     parent_fractal_pos: &Complex<i32>,
     fractal_lattice: &HashMap<Complex<i32>, Fractal>,
+    coefficients: &HashMap<Complex<i32>, C>,
     global_position_map: &Vec<HashMap<Complex<i32>, Complex<i32>>>,
     value_prediction_params: &Vec<[f32; 6]>,
     width_prediction_params: &Vec<[f32; 6]>,
-    channel: usize,
-) -> (usize, i32) {
+) -> (f32, i32) {
     assert!(current_depth > 0);
 
     let depth = fractal_lattice[parent_fractal_pos].depth;
@@ -183,8 +270,8 @@ pub fn get_hf_context_bucket(
         current_depth,
         parent_fractal_pos,
         fractal_lattice,
+        coefficients,
         global_position_map,
-        channel,
     );
 
     let width = width_prediction_params_layer[0]
@@ -194,8 +281,6 @@ pub fn get_hf_context_bucket(
         + width_prediction_params_layer[4] * ((values[1] - values[5]).abs() as f32)
         + width_prediction_params_layer[5] * ((values[2] - values[4]).abs() as f32);
 
-    let bucket = assign_bucket(width);
-
     let prediction = (values[0] as f32) * value_prediction_params_layer[0]
         + (values[1] as f32) * value_prediction_params_layer[1]
         + (values[2] as f32) * value_prediction_params_layer[2]
@@ -203,7 +288,7 @@ pub fn get_hf_context_bucket(
         + (values[4] as f32) * value_prediction_params_layer[4]
         + (values[5] as f32) * value_prediction_params_layer[5];
 
-    (bucket as usize, prediction as i32)
+    (width, prediction as i32)
 }
 
 fn get_entropy(histogram: &[u32], total_size: usize) -> f32 {
@@ -221,89 +306,154 @@ pub fn laplace_distribution(x: f32, center: f32, width: f32) -> f32 {
     (-(x-center).abs()/width).exp()/(2.0*width)
 }
 
-pub fn encode(
-    wavelet_image: &mut WaveletImage,
-    encoder_opts: &mut EncoderOpts,
-) -> Result<[Vec<AnsContext>; 3], String> {
-    let mut contexts: [Vec<AnsContext>; 3] = [vec![], vec![], vec![]];
+struct ChannelPrediction {
+    contexts: Vec<AnsContext>,
+    value_prediction_params: Vec<[f32; 6]>,
+    width_prediction_params: Vec<[f32; 6]>,
+    // Fractal, haar tree position and (bucket, prediction) of every coded coefficient
+    predictors: Vec<(Complex<i32>, usize, (usize, i32))>,
+}
+
+/*
+ * Predictions depend only on the coefficients, never on other predictions, so every
+ * channel and every position of a level can be predicted independently. Results are
+ * collected in scan order, which keeps the output identical for any thread count.
+ */
+fn predict_channel(
+    wavelet_image: &WaveletImage,
+    channel: usize,
+    encoder_opts: &EncoderOpts,
+) -> ChannelPrediction {
     let mut ctx_mod = ContextModeler::new();
-    let sorted_lattice = wavelet_image.get_sorted_lattice().clone();
-    for channel in 0..wavelet_image.metadata.colorspace.num_channels() {
-        ctx_mod.optimize_parameters(&wavelet_image, channel);
-
-        encoder_opts.value_prediction_params[channel] = ctx_mod.value_predictors[channel].clone();
-        encoder_opts.width_prediction_params[channel] = ctx_mod.width_predictors[channel].clone();
-
-        contexts[channel] = vec![AnsContext::new(); CONTEXT_AMOUNT];
-        let mut mse: Vec<i32> = vec![];
-        let depth = wavelet_image.fractal_lattice[&sorted_lattice[0][0]].depth;
-
-        for (i, image_pos) in sorted_lattice[0].iter().enumerate() {
-            let fractal = &wavelet_image.fractal_lattice.get(image_pos).unwrap();
-            let haar_tree_pos = fractal.position_map[0].get(&image_pos).unwrap();
-            if let Some(value) = fractal.coefficients[channel][0] {
-                let (bucket, prediction) =
-                    get_lf_context_bucket(0, 0, image_pos, &wavelet_image.fractal_lattice, channel);
-                let residual = value - prediction;
-                {
-                    let mut mut_frac = wavelet_image.fractal_lattice.get_mut(image_pos).unwrap();
-                    mut_frac.parameter_predictors[channel][0] = (bucket, prediction);
-                    contexts[channel][bucket].bump_freq(utils::pack_signed(residual));
-                }
-            }
-        }
+    ctx_mod.optimize_parameters(wavelet_image, channel);
+    let value_prediction_params = ctx_mod.value_predictors[channel].clone();
+    let width_prediction_params = ctx_mod.width_predictors[channel].clone();
+
+    let sorted_lattice = wavelet_image.get_sorted_lattice();
+    let coefficients = wavelet_image.get_channel_coefficients(channel);
+    // Fractal, haar tree position, residual, width and prediction of every coded coefficient
+    let mut coded = vec![];
+    let mut mse: Vec<i32> = vec![];
+    let depth = wavelet_image.fractal_lattice[&sorted_lattice[0][0]].depth;
+
+    // First scan -> Low frequency coefficients, second scan -> High frequency coefficient root
+    for haar_tree_pos in 0..2 {
+        let level_predictions: Vec<_> = sorted_lattice[0]
+            .par_iter()
+            .filter_map(|image_pos| {
+                let fractal = &wavelet_image.fractal_lattice[image_pos];
+                fractal.coefficients[channel][haar_tree_pos].map(|value| {
+                    let prediction = get_lf_context(
+                        haar_tree_pos,
+                        0,
+                        image_pos,
+                        &wavelet_image.fractal_lattice,
+                        &coefficients,
+                    );
+                    (*image_pos, haar_tree_pos, value, prediction)
+                })
+            })
+            .collect();
 
-        // Second scan -> High frequency coefficient root
-        for (i, image_pos) in sorted_lattice[0].iter().enumerate() {
-            let fractal = &wavelet_image.fractal_lattice.get(image_pos).unwrap();
-            let haar_tree_pos = fractal.position_map[0].get(&image_pos).unwrap();
-            if let Some(value) = fractal.coefficients[channel][1] {
-                let (bucket, prediction) =
-                    get_lf_context_bucket(1, 0, image_pos, &wavelet_image.fractal_lattice, channel);
-                let residual = value - prediction;
-                {
-                    let mut mut_frac = wavelet_image.fractal_lattice.get_mut(image_pos).unwrap();
-                    mut_frac.parameter_predictors[channel][1] = (bucket, prediction);
-                    contexts[channel][bucket].bump_freq(utils::pack_signed(residual));
-                }
-            }
+        for (image_pos, haar_tree_pos, value, (width, prediction)) in level_predictions {
+            coded.push((image_pos, haar_tree_pos, value - prediction, width, prediction));
         }
+    }
 
-        for level in (1..depth).rev() {
-            for (i, image_pos) in sorted_lattice[level as usize].iter().enumerate() {
-                let parent_pos = wavelet_image.global_position_map[level as usize][&image_pos];
-                let fractal = &wavelet_image.fractal_lattice.get(&parent_pos).unwrap();
-                let haar_tree_pos = fractal.position_map[level as usize]
-                    .get(&image_pos)
-                    .unwrap()
-                    .clone();
-                if let Some(value) = fractal.coefficients[channel][haar_tree_pos] {
-                    let (bucket, prediction) = get_hf_context_bucket(
+    for level in (1..depth).rev() {
+        let level_predictions: Vec<_> = sorted_lattice[level as usize]
+            .par_iter()
+            .filter_map(|image_pos| {
+                let parent_pos = wavelet_image.global_position_map[level as usize][image_pos];
+                let fractal = &wavelet_image.fractal_lattice[&parent_pos];
+                let haar_tree_pos = fractal.position_map[level as usize][image_pos];
+                fractal.coefficients[channel][haar_tree_pos].map(|value| {
+                    let prediction = get_hf_context(
                         *image_pos,
                         level,
                         &parent_pos,
                         &wavelet_image.fractal_lattice,
+                        &coefficients,
                         &wavelet_image.global_position_map,
-                        &encoder_opts.value_prediction_params[channel],
-                        &encoder_opts.width_prediction_params[channel],
-                        channel,
+                        &value_prediction_params,
+                        &width_prediction_params,
                     );
-                    let residual = value - prediction;
-                    mse.push((residual).pow(2));
-                    contexts[channel][bucket].bump_freq(utils::pack_signed(residual));
-                    let mut mut_frac = wavelet_image.fractal_lattice.get_mut(&parent_pos).unwrap();
-                    mut_frac.parameter_predictors[channel][haar_tree_pos] = (bucket, prediction);
-                }
-            }
+                    (parent_pos, haar_tree_pos, value, prediction)
+                })
+            })
+            .collect();
+
+        for (parent_pos, haar_tree_pos, value, (width, prediction)) in level_predictions {
+            let residual = value - prediction;
+            mse.push((residual).pow(2));
+            coded.push((parent_pos, haar_tree_pos, residual, width, prediction));
+        }
+    }
+
+    emit_mse(&mse, channel);
+
+    let samples: Vec<(f32, u32)> = coded
+        .iter()
+        .map(|&(_, _, residual, width, _)| (width, utils::pack_signed(residual)))
+        .collect();
+    let mut contexts: Vec<AnsContext> = cluster_buckets(&samples, encoder_opts.contexts)
+        .into_iter()
+        .map(|(min_width, width)| AnsContext::for_bucket(min_width, width))
+        .collect();
+
+    let mut predictors = vec![];
+    for (position, haar_tree_pos, residual, width, prediction) in coded {
+        let bucket = assign_bucket(width, &contexts);
+        contexts[bucket].bump_freq(utils::pack_signed(residual));
+        predictors.push((position, haar_tree_pos, (bucket, prediction)));
+    }
+
+    for (i, ctx) in contexts.iter_mut().enumerate() {
+        ctx.max_freq_bits =
+            utils::get_prev_power_two(ctx.freqs.iter().sum::<u32>().max(1) as usize).trailing_zeros();
+        // The binary coder adapts on its own and only needs the Laplace widths
+        match (encoder_opts.entropy_coder, encoder_opts.adaptive) {
+            (EntropyCoder::Binary, _) => *ctx = AnsContext::for_bucket(ctx.min_width, ctx.width),
+            (EntropyCoder::Rans, true) => *ctx = AnsContext::adaptive_prior(ctx.min_width, ctx.width),
+            (EntropyCoder::Rans, false) => ctx.finalize_cheapest(),
         }
 
-        emit_mse(&mse, channel);
+        if encoder_opts.emit_coefficients {
+            emit_coefficients(&ctx.freqs, i, channel)
+        }
+    }
+
+    ChannelPrediction {
+        contexts,
+        value_prediction_params,
+        width_prediction_params,
+        predictors,
+    }
+}
+
+pub fn encode(
+    wavelet_image: &mut WaveletImage,
+    encoder_opts: &mut EncoderOpts,
+) -> Result<[Vec<AnsContext>; 3], String> {
+    let mut contexts: [Vec<AnsContext>; 3] = [vec![], vec![], vec![]];
+    let image: &WaveletImage = wavelet_image;
+    let opts: &EncoderOpts = encoder_opts;
+    let channel_predictions: Vec<ChannelPrediction> = (0..image.metadata.colorspace.num_channels())
+        .into_par_iter()
+        .map(|channel| predict_channel(image, channel, opts))
+        .collect();
+
+    for (channel, prediction) in channel_predictions.into_iter().enumerate() {
+        encoder_opts.value_prediction_params[channel] = prediction.value_prediction_params;
+        encoder_opts.width_prediction_params[channel] = prediction.width_prediction_params;
 
-        for (i, ctx) in contexts[channel].iter_mut().enumerate() {
-            ctx.max_freq_bits =
-                utils::get_prev_power_two(ctx.freqs.iter().sum::<u32>() as usize).trailing_zeros();
-            ctx.finalize_context(true, i);
-            if encoder_opts.verbose {
+        for (fractal_pos, haar_tree_pos, predictor) in prediction.predictors {
+            let fractal = wavelet_image.fractal_lattice.get_mut(&fractal_pos).unwrap();
+            fractal.parameter_predictors[channel][haar_tree_pos] = predictor;
+        }
+
+        if encoder_opts.verbose {
+            for ctx in prediction.contexts.iter() {
                 println!(
                     "CHANNEL: {}, size: {}, entropy: {}",
                     channel,
@@ -311,13 +461,47 @@ pub fn encode(
                     get_entropy(&ctx.freqs, ctx.freqs.iter().sum::<u32>() as usize)
                 );
             }
-
-            if encoder_opts.emit_coefficients {
-                emit_coefficients(&ctx.freqs, i, channel)
-            }
         }
+        contexts[channel] = prediction.contexts;
     }
-    //dbg!(&ctx_mod);
 
     Ok(contexts)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn clustering_separates_distributions() {
+        let narrow = (0..2700).map(|i| (1. + (i / 3 % 3) as f32, utils::pack_signed(i % 3 - 1)));
+        let wide = (0..4050).map(|i| (40. + (i / 81 % 5) as f32, utils::pack_signed(i % 81 - 40)));
+        let samples: Vec<(f32, u32)> = narrow.chain(wide).collect();
+
+        let buckets = cluster_buckets(&samples, None);
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0].0, 0);
+        assert!(buckets[1].0 > 3 && buckets[1].0 <= 40);
+        assert!(buckets[0].1 < 1. && buckets[1].1 > 10.);
+
+        assert_eq!(cluster_buckets(&samples, Some(5)).len(), 5);
+        assert_eq!(cluster_buckets(&[], None), DEFAULT_BUCKETS.to_vec());
+    }
+
+    #[test]
+    fn context_count_does_not_change_output() {
+        use crate::test_images::{self, noisy_gradient};
+
+        let (width, height) = (37, 23);
+        let data = noisy_gradient(width, height, 63);
+        let encode = |contexts| {
+            test_images::encode(EncoderOpts { contexts, ..Default::default() }, width, height, &data)
+        };
+
+        for contexts in [None, Some(1), Some(16)] {
+            assert_eq!(test_images::decode(encode(contexts)).data, data);
+        }
+        // Clustering must not depend on anything but the residuals
+        assert_eq!(encode(None), encode(None));
+    }
+}
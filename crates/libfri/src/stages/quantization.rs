@@ -1,41 +1,91 @@
+use crate::encoder::{EncoderOpts, EncoderQuality};
 use crate::{stages::wavelet_transform::WaveletImage, utils};
 
-fn get_quantization_matrix() -> [i32; 32] {
-    return [1; 32];
+pub const QUANTIZATION_LAYERS: usize = 32;
+
+// Coarsest step accepted by the decoder, the encoder never goes past a few hundred
+pub const MAX_QUANTIZATION_STEP: i32 = 1 << 10;
+
+/*
+ * Reconstruction point of a dequantized coefficient expressed in eighths of a
+ * quantization step. Residuals follow a Laplace distribution, so reconstructing
+ * slightly below the middle of the quantization bin lowers the expected error.
+ */
+const RECONSTRUCTION_OFFSET: i32 = 3;
+
+/*
+ * Quantization steps per haar tree layer. Layer 0 holds the low frequency coefficient,
+ * layer n holds the high frequency coefficients of tree level n - 1, so the finest
+ * details are at the end of the table. Coarse layers affect large parts of the fractal
+ * and are therefore quantized more gently.
+ */
+static HIGH_QUALITY_STEPS: [i32; 10] = [1, 1, 1, 1, 1, 2, 2, 3, 4, 6];
+static MEDIUM_QUALITY_STEPS: [i32; 10] = [1, 1, 1, 2, 2, 3, 4, 6, 8, 12];
+static LOW_QUALITY_STEPS: [i32; 10] = [2, 2, 2, 3, 4, 6, 8, 12, 16, 24];
+
+pub fn get_quantization_matrix(quality: &EncoderQuality, scale: f32) -> [i32; QUANTIZATION_LAYERS] {
+    let steps: &[i32] = match quality {
+        EncoderQuality::Lossless => &[],
+        EncoderQuality::High => &HIGH_QUALITY_STEPS,
+        EncoderQuality::Medium => &MEDIUM_QUALITY_STEPS,
+        EncoderQuality::Low => &LOW_QUALITY_STEPS,
+    };
+
+    let mut matrix = [1; QUANTIZATION_LAYERS];
+    matrix[..steps.len()].copy_from_slice(steps);
+    // Layers deeper than the table reuse its finest step
+    if let Some(&finest) = steps.last() {
+        matrix[steps.len()..].fill(finest);
+    }
+    matrix.map(|step| ((step as f32 * scale).round() as i32).max(1))
+}
+
+#[inline]
+fn get_layer(haar_tree_position: usize) -> usize {
+    if haar_tree_position == 0 {
+        0
+    } else {
+        utils::get_prev_power_two(haar_tree_position).trailing_zeros() as usize + 1
+    }
+}
+
+#[inline]
+fn quantize(coef: i32, step: i32) -> i32 {
+    coef.signum() * (coef.abs() / step)
+}
+
+#[inline]
+fn dequantize(coef: i32, step: i32) -> i32 {
+    if step == 1 || coef == 0 {
+        coef
+    } else {
+        coef.signum() * (coef.abs() * step + step * RECONSTRUCTION_OFFSET / 8)
+    }
 }
 
-pub fn encode(mut image: WaveletImage) -> Result<WaveletImage, String> {
-    let quantization_matrix = get_quantization_matrix();
+pub fn encode(mut image: WaveletImage, encoder_opts: &EncoderOpts) -> Result<WaveletImage, String> {
+    let quantization_matrix = get_quantization_matrix(&encoder_opts.quality, encoder_opts.quantization_scale);
     for (_, fractal) in &mut image.fractal_lattice {
-        for (channel, channel_coef) in fractal.coefficients.iter_mut().enumerate() {
+        for channel_coef in fractal.coefficients.iter_mut() {
             for (i, coef_opt) in channel_coef.iter_mut().enumerate() {
                 if let Some(coef) = coef_opt {
-                    let layer = utils::get_prev_power_two(i + 1).trailing_zeros();
-                        //if layer > 5 {
-                        //    *coef = 0;
-                        //}
-                        *coef /=  quantization_matrix[layer as usize];
-                            //(layer as i32)/5 + 1;
+                    *coef = quantize(*coef, quantization_matrix[get_layer(i)]);
                 }
             }
         }
     }
+    image.quantization_matrix = quantization_matrix;
 
     Ok(image)
 }
 
 pub fn decode(mut image: WaveletImage) -> Result<WaveletImage, String> {
-    let quantization_matrix = get_quantization_matrix();
+    let quantization_matrix = image.quantization_matrix;
     for (_, fractal) in &mut image.fractal_lattice {
-        for (channel, channel_coef) in fractal.coefficients.iter_mut().enumerate() {
+        for channel_coef in fractal.coefficients.iter_mut() {
             for (i, coef_opt) in channel_coef.iter_mut().enumerate() {
                 if let Some(coef) = coef_opt {
-                    let layer = utils::get_prev_power_two(i + 1).trailing_zeros();
-                        //if layer > 5 {
-                        //    *coef = 0;
-                        //}
-                        *coef /=  quantization_matrix[layer as usize];
-                            //(layer as i32)/5 + 1;
+                    *coef = dequantize(*coef, quantization_matrix[get_layer(i)]);
                 }
             }
         }
@@ -43,3 +93,40 @@ pub fn decode(mut image: WaveletImage) -> Result<WaveletImage, String> {
 
     Ok(image)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lossless_matrix_is_identity() {
+        let matrix = get_quantization_matrix(&EncoderQuality::Lossless, 1.);
+        for coef in -300..300 {
+            assert_eq!(dequantize(quantize(coef, matrix[9]), matrix[9]), coef);
+        }
+    }
+
+    #[test]
+    fn dequantization_stays_within_step() {
+        let step = 12;
+        for coef in -300..300 {
+            let reconstructed = dequantize(quantize(coef, step), step);
+            assert!((reconstructed - coef).abs() < step, "{coef} -> {reconstructed}");
+        }
+    }
+
+    #[test]
+    fn scaled_matrix_never_drops_below_one() {
+        assert_eq!(get_quantization_matrix(&EncoderQuality::Low, 0.), [1; QUANTIZATION_LAYERS]);
+        assert_eq!(get_quantization_matrix(&EncoderQuality::Low, 2.)[9], 48);
+    }
+
+    #[test]
+    fn layers_follow_haar_tree_levels() {
+        assert_eq!(get_layer(0), 0);
+        assert_eq!(get_layer(1), 1);
+        assert_eq!(get_layer(2), 2);
+        assert_eq!(get_layer(3), 2);
+        assert_eq!(get_layer(511), 9);
+    }
+}
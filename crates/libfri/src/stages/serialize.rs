@@ -1,257 +1,903 @@
-use itertools::Itertools;
+use crc32fast::Hasher;
 use num::traits::ToBytes;
-use std::array::TryFromSliceError;
-use std::error::Error;
-use std::fmt::Display;
-use std::mem;
-
-use crate::images::{ChannelData, ColorSpace, CompressedImage, FractalVariant, ImageMetadata};
-use crate::stages::entropy_coding::{AnsContext, ALPHABET_SIZE};
-
-#[derive(Debug)]
-pub enum SerializeError {
-    InvalidSignature,
-    InvalidMetadata,
-    MalformedImageBytes,
-    SliceConversion(TryFromSliceError),
-}
-
-impl Display for SerializeError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        use SerializeError::*;
-        let var_name = match self {
-            InvalidSignature => write!(f, "Invalid signature for FRIF image."),
-            SliceConversion(e) => write!(f, "Slice from input bytes is malformed: {}", e),
-            InvalidMetadata => write!(f, "Invalid metadata"),
-            MalformedImageBytes => write!(f, "Malformed image bytes"),
-        };
-        var_name
-    }
-}
+use std::io::{self, Read, Write};
 
-impl From<TryFromSliceError> for SerializeError {
-    fn from(err: TryFromSliceError) -> Self {
-        SerializeError::SliceConversion(err)
-    }
-}
-impl Error for SerializeError {}
+use crate::error::{check_dimensions, FriError};
+use crate::utils;
+use crate::images::{
+    ChannelData, ColorSpace, CompressedImage, EmbeddedMetadata, EncodedFrame, EncodedTile,
+    EntropyCoder, FractalVariant, ImageMetadata, Region, SequenceImage, TiledImage,
+};
+use crate::stages::entropy_coding::{
+    AnsContext, ALPHABET_SIZE, MAX_FREQ_BITS, MAX_HISTOGRAM_BITS, MIN_HISTOGRAM_BITS,
+};
+use crate::stages::prediction::{DEFAULT_BUCKETS, MAX_CONTEXTS, MIN_CONTEXT_WIDTH};
+use crate::stages::quantization::{MAX_QUANTIZATION_STEP, QUANTIZATION_LAYERS};
 
 #[allow(non_snake_case, non_upper_case_globals)]
 mod Segments {
+    pub const QNT: &[u8] = &[0xFF, 0xB0]; // Quantization matrix
+    pub const FGR: &[u8] = &[0xFF, 0xB1]; // Fractal Group size
     pub const EHD: &[u8] = &[0xFF, 0xB2]; // Entropy Header Data
+    pub const EHT: &[u8] = &[0xFF, 0xB3]; // Entropy Histogram Table
     pub const DAT: &[u8] = &[0xFF, 0xB4]; // Data
+    pub const CTX: &[u8] = &[0xFF, 0xB5]; // Context buckets
+    pub const GIX: &[u8] = &[0xFF, 0xB6]; // Group Index
     pub const EOC: &[u8] = &[0xFF, 0xB8]; // End Of Channel
+    pub const TIL: &[u8] = &[0xFF, 0xBA]; // Tile
     pub const PRD: &[u8] = &[0xFF, 0xBB]; // Prediction params
+    pub const ICC: &[u8] = &[0xFF, 0xBC]; // ICC color profile
+    pub const EXF: &[u8] = &[0xFF, 0xBD]; // EXIF metadata
+    pub const XMP: &[u8] = &[0xFF, 0xBE]; // XMP metadata
+    pub const FRM: &[u8] = &[0xFF, 0xBF]; // Frame of a sequence
     pub const EOI: &[u8] = &[0xFF, 0xDF]; // End Of Image
+
+    pub fn get_name(marker: &[u8]) -> &'static str {
+        match marker {
+            QNT => "QNT",
+            FGR => "FGR",
+            EHD => "EHD",
+            EHT => "EHT",
+            DAT => "DAT",
+            CTX => "CTX",
+            GIX => "GIX",
+            EOC => "EOC",
+            TIL => "TIL",
+            PRD => "PRD",
+            ICC => "ICC",
+            EXF => "EXF",
+            XMP => "XMP",
+            FRM => "FRM",
+            EOI => "EOI",
+            _ => "unknown",
+        }
+    }
 }
 
-pub fn encode(mut image: CompressedImage) -> Result<Vec<u8>, SerializeError> {
-    let mut serial = Vec::new();
-    serial.reserve(mem::size_of_val(&image));
+/*
+ * Header word packs colorspace (bits 30-31), variant (bits 28-29), feature flags
+ * (bits 8-27) and the format version (bits 0-7). Flags mark features changing how
+ * the stream has to be read, a decoder refuses streams with flags it does not know.
+ * Optional data goes into segments of its own, which older decoders skip.
+ * Version 2 allows EHT segments in place of EHD for contexts coded with an explicit
+ * histogram, nothing else changed, so the reader does not distinguish the two.
+ * Version 3 stores the quantization matrix compactly, older QNT segments hold
+ * every step as a u32.
+ */
+pub const FORMAT_VERSION: u8 = 3;
+// First version with the compact quantization matrix
+const COMPACT_QNT_VERSION: u8 = 3;
+const VERSION_MASK: u32 = 0xFF;
+const FEATURE_MASK: u32 = 0x0FFF_FF00;
+
+// Every segment is followed by its CRC32
+const FEATURE_CHECKSUMS: u32 = 1 << 27;
+// Contexts are adaptive and have no EHD or EHT segments
+const FEATURE_ADAPTIVE: u32 = 1 << 26;
+// Channels may replace the default context buckets with a CTX segment
+const FEATURE_CONTEXTS: u32 = 1 << 25;
+// Residuals are coded with the binary arithmetic coder, which has no EHD or EHT segments
+const FEATURE_BINARY_CODER: u32 = 1 << 24;
+const KNOWN_FEATURES: u32 = FEATURE_CHECKSUMS | FEATURE_ADAPTIVE | FEATURE_CONTEXTS | FEATURE_BINARY_CODER;
 
-    serial.extend_from_slice(b"frif");
-    serial.extend_from_slice(&image.metadata.height.to_le_bytes());
-    serial.extend_from_slice(&image.metadata.width.to_le_bytes());
+fn get_buckets(contexts: &[AnsContext]) -> Vec<(u32, f32)> {
+    contexts.iter().map(|ctx| (ctx.min_width, ctx.width)).collect()
+}
+
+/*
+ * Writes segments of the stream. Since version 1 every marker is followed by
+ * the LEB128 encoded length of the segment contents, so the contents are
+ * buffered until the segment ends. With checksums enabled each segment is
+ * followed by the CRC32 of its marker, length and contents, and the end of
+ * image by the CRC32 of the whole stream.
+ */
+pub struct SegmentWriter<W: Write> {
+    writer: W,
+    checksums: bool,
+    marker: &'static [u8],
+    contents: Vec<u8>,
+    file: Hasher,
+}
 
-    let mut mdat: u32 = 0x0;
+impl<W: Write> SegmentWriter<W> {
+    pub fn new(writer: W, checksums: bool) -> Self {
+        SegmentWriter { writer, checksums, marker: &[], contents: vec![], file: Hasher::new() }
+    }
+
+    fn write_raw(&mut self, bytes: &[u8]) -> Result<(), FriError> {
+        if self.checksums {
+            self.file.update(bytes);
+        }
+        self.writer.write_all(bytes)?;
+        Ok(())
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), FriError> {
+        match self.marker {
+            [] => self.write_raw(bytes),
+            _ => {
+                self.contents.extend_from_slice(bytes);
+                Ok(())
+            }
+        }
+    }
+
+    fn begin_segment(&mut self, marker: &'static [u8]) -> Result<(), FriError> {
+        self.marker = marker;
+        self.contents.clear();
+        Ok(())
+    }
+
+    fn write_segment_start(&mut self) -> Result<Hasher, FriError> {
+        let mut prefix = self.marker.to_vec();
+        utils::write_leb128(self.contents.len() as u64, &mut prefix);
+        self.marker = &[];
+        self.write_raw(&prefix)?;
+
+        let mut segment = Hasher::new();
+        segment.update(&prefix);
+        Ok(segment)
+    }
+
+    fn end_segment(&mut self) -> Result<(), FriError> {
+        let mut segment = self.write_segment_start()?;
+        let contents = std::mem::take(&mut self.contents);
+        self.write_raw(&contents)?;
+        if self.checksums {
+            segment.update(&contents);
+            self.write_raw(&segment.finalize().to_le_bytes())?;
+        }
+        self.contents = contents;
+        Ok(())
+    }
+
+    fn end_image(&mut self) -> Result<(), FriError> {
+        self.begin_segment(Segments::EOI)?;
+        self.write_segment_start()?;
+        if self.checksums {
+            let checksum = self.file.clone().finalize();
+            self.writer.write_all(&checksum.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+pub fn encode_header<W: Write>(writer: &mut SegmentWriter<W>, metadata: &ImageMetadata) -> Result<(), FriError> {
+    write_header(writer, metadata, 0)
+}
+
+fn write_header<W: Write>(
+    writer: &mut SegmentWriter<W>,
+    metadata: &ImageMetadata,
+    features: u32,
+) -> Result<(), FriError> {
+    writer.write_all(b"frif")?;
+    writer.write_all(&metadata.height.to_le_bytes())?;
+    writer.write_all(&metadata.width.to_le_bytes())?;
+
+    let mut mdat: u32 = FORMAT_VERSION as u32 | features;
 
     // colorspace
-    let colorspace = &image.metadata.colorspace.get_encoding();
+    let colorspace = &metadata.colorspace.get_encoding();
     mdat |= colorspace << 30;
 
     // variant
-    let variant = &image.metadata.variant.get_encoding();
+    let variant = &metadata.variant.get_encoding();
     mdat |= variant << 28;
 
-    serial.extend_from_slice(&mdat.to_le_bytes());
+    if writer.checksums {
+        mdat |= FEATURE_CHECKSUMS;
+    }
+
+    writer.write_all(&mdat.to_le_bytes())?;
+
+    let EmbeddedMetadata { icc_profile, exif, xmp } = &metadata.embedded;
+    for (marker, contents) in [(Segments::ICC, icc_profile), (Segments::EXF, exif), (Segments::XMP, xmp)] {
+        if let Some(contents) = contents {
+            writer.begin_segment(marker)?;
+            writer.write_all(contents)?;
+            writer.end_segment()?;
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Pulls fields of the stream one at a time, so only the segment being parsed
+ * has to be kept in memory.
+ */
+struct SegmentReader<R: Read> {
+    reader: R,
+    // Name of the segment being read, reported when the stream ends too early
+    segment: &'static str,
+    version: u8,
+    // Bytes left in the current segment, unbounded in version 0 streams
+    remaining: Option<u64>,
+    // Stream carries checksums, they are skipped unless verify is set
+    checksums: bool,
+    adaptive: bool,
+    entropy_coder: EntropyCoder,
+    verify: bool,
+    segment_hasher: Hasher,
+    file_hasher: Hasher,
+}
+
+impl<R: Read> SegmentReader<R> {
+    fn new(reader: R, verify: bool) -> Self {
+        SegmentReader {
+            reader,
+            segment: "header",
+            version: 0,
+            remaining: None,
+            checksums: false,
+            adaptive: false,
+            entropy_coder: EntropyCoder::Rans,
+            verify,
+            segment_hasher: Hasher::new(),
+            file_hasher: Hasher::new(),
+        }
+    }
+
+    fn hash(&mut self, bytes: &[u8]) {
+        if self.verify {
+            self.segment_hasher.update(bytes);
+            self.file_hasher.update(bytes);
+        }
+    }
+
+    fn truncated(&self, err: io::Error) -> FriError {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => FriError::TruncatedSegment(self.segment),
+            _ => FriError::Io(err),
+        }
+    }
+
+    fn consume(&mut self, len: u64) -> Result<(), FriError> {
+        if let Some(remaining) = self.remaining {
+            self.remaining = Some(remaining.checked_sub(len).ok_or(FriError::MalformedSegment(self.segment))?);
+        }
+        Ok(())
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], FriError> {
+        self.consume(N as u64)?;
+        let mut buf = [0; N];
+        self.reader.read_exact(&mut buf).map_err(|e| self.truncated(e))?;
+        self.hash(&buf);
+        Ok(buf)
+    }
+
+    fn read_leb128(&mut self) -> Result<u64, FriError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let [byte] = self.read_array()?;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(FriError::MalformedSegment(self.segment))
+    }
+
+    /*
+     * Reads the marker of the next segment the decoder understands, segments
+     * with unknown markers are skipped in streams that store their lengths.
+     */
+    fn read_marker(&mut self) -> Result<[u8; 2], FriError> {
+        loop {
+            self.segment = "segment marker";
+            self.segment_hasher.reset();
+            self.remaining = None;
+            let marker = self.read_array()?;
+            self.segment = Segments::get_name(&marker);
+            if self.version == 0 {
+                return Ok(marker);
+            }
+
+            let len = self.read_leb128()?;
+            self.remaining = Some(len);
+            if self.segment != "unknown" {
+                return Ok(marker);
+            }
+            self.end_segment()?;
+        }
+    }
+
+    fn read_u32(&mut self) -> Result<u32, FriError> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, FriError> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_f32(&mut self) -> Result<f32, FriError> {
+        Ok(f32::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_bytes(&mut self, len: u64) -> Result<Vec<u8>, FriError> {
+        self.consume(len)?;
+        // Length comes from the stream, so the buffer grows with the data actually read
+        let mut bytes = vec![];
+        (&mut self.reader).take(len).read_to_end(&mut bytes).map_err(|e| self.truncated(e))?;
+        if (bytes.len() as u64) < len {
+            return Err(FriError::TruncatedSegment(self.segment));
+        }
+        self.hash(&bytes);
+        Ok(bytes)
+    }
+
+    // Segments storing a single blob take up all of their contents
+    fn read_rest(&mut self) -> Result<Vec<u8>, FriError> {
+        match self.remaining {
+            Some(remaining) => self.read_bytes(remaining),
+            None => Err(FriError::MalformedSegment(self.segment)),
+        }
+    }
+
+    /*
+     * Contents a newer encoder appended to a known segment are skipped along
+     * with whole unknown segments.
+     */
+    fn skip_rest(&mut self) -> Result<(), FriError> {
+        if let Some(remaining) = self.remaining {
+            self.read_bytes(remaining)?;
+        }
+        self.remaining = None;
+        Ok(())
+    }
+
+    fn end_segment(&mut self) -> Result<(), FriError> {
+        self.skip_rest()?;
+        if !self.checksums {
+            return Ok(());
+        }
+        let expected = self.segment_hasher.clone().finalize();
+        if self.read_u32()? != expected && self.verify {
+            return Err(FriError::ChecksumMismatch(self.segment));
+        }
+        Ok(())
+    }
+
+    fn end_image(&mut self) -> Result<(), FriError> {
+        self.skip_rest()?;
+        if !self.checksums {
+            return Ok(());
+        }
+        let expected = self.file_hasher.clone().finalize();
+        if self.read_u32()? != expected && self.verify {
+            return Err(FriError::ChecksumMismatch("whole file"));
+        }
+        Ok(())
+    }
+}
+
+fn decode_header<R: Read>(reader: &mut SegmentReader<R>) -> Result<ImageMetadata, FriError> {
+    reader.segment = "header";
+    if &reader.read_array::<4>()? != b"frif" {
+        return Err(FriError::InvalidHeader("invalid signature"));
+    }
+
+    let height = reader.read_u32()?;
+    let width = reader.read_u32()?;
+    let metadata = reader.read_u32()?;
+    check_dimensions(width, height)?;
+
+    reader.version = (metadata & VERSION_MASK) as u8;
+    if reader.version > FORMAT_VERSION {
+        return Err(FriError::Unsupported("format version"));
+    }
+    if metadata & FEATURE_MASK & !KNOWN_FEATURES != 0 {
+        return Err(FriError::Unsupported("feature flags"));
+    }
+    reader.checksums = metadata & FEATURE_CHECKSUMS != 0;
+    reader.adaptive = metadata & FEATURE_ADAPTIVE != 0;
+    if metadata & FEATURE_BINARY_CODER != 0 {
+        if reader.adaptive {
+            return Err(FriError::Unsupported("feature flags"));
+        }
+        reader.entropy_coder = EntropyCoder::Binary;
+    }
+
+    let colorspace = ColorSpace::from_encoding((metadata >> 30 & 0b11) as u8)?;
+    let variant = FractalVariant::from_encoding((metadata >> 28 & 0b11) as u8)?;
+
+    Ok(ImageMetadata {
+        height,
+        width,
+        colorspace,
+        variant,
+        embedded: EmbeddedMetadata::default(),
+    })
+}
+
+/*
+ * Embedded metadata segments directly follow the header, returns the marker of
+ * the first segment after them.
+ */
+fn decode_embedded<R: Read>(
+    reader: &mut SegmentReader<R>,
+    embedded: &mut EmbeddedMetadata,
+) -> Result<[u8; 2], FriError> {
+    loop {
+        let marker = reader.read_marker()?;
+        let blob = match &marker[..] {
+            Segments::ICC => &mut embedded.icc_profile,
+            Segments::EXF => &mut embedded.exif,
+            Segments::XMP => &mut embedded.xmp,
+            _ => return Ok(marker),
+        };
+        *blob = Some(reader.read_rest()?);
+        reader.end_segment()?;
+    }
+}
+
+pub fn encode(image: CompressedImage, checksums: bool) -> Result<Vec<u8>, FriError> {
+    let mut serial = Vec::new();
+    encode_to(image, &mut SegmentWriter::new(&mut serial, checksums))?;
+    Ok(serial)
+}
+
+pub fn encode_to<W: Write>(mut image: CompressedImage, writer: &mut SegmentWriter<W>) -> Result<(), FriError> {
+    let mut features = 0;
+    if image.entropy_coder == EntropyCoder::Binary {
+        features |= FEATURE_BINARY_CODER;
+    }
+    for channel in image.channel_data.iter().flatten() {
+        if channel.ans_contexts.iter().any(|ctx| ctx.adaptive) {
+            features |= FEATURE_ADAPTIVE;
+        }
+        if get_buckets(&channel.ans_contexts) != DEFAULT_BUCKETS {
+            features |= FEATURE_CONTEXTS;
+        }
+    }
+    write_header(writer, &image.metadata, features)?;
 
+    // Lossless images leave out the quantization matrix, the decoder defaults to unit steps
+    if image.quantization_matrix.iter().any(|&step| step != 1) {
+        writer.begin_segment(Segments::QNT)?;
+        writer.write_all(&encode_quantization_matrix(&image.quantization_matrix))?;
+        writer.end_segment()?;
+    }
+
+    writer.begin_segment(Segments::FGR)?;
+    writer.write_all(&image.group_size.to_le_bytes())?;
+    writer.end_segment()?;
+
+    let entropy_coder = image.entropy_coder;
     let mut i = 0;
     while let Some(ChannelData {
         ans_contexts,
         data,
+        group_offsets,
         value_prediction_parameters,
         width_prediction_parameters,
     }) = &image.channel_data[i].take()
     {
         i += 1;
 
-        serial.extend_from_slice(Segments::PRD);
-        serial.extend_from_slice(
+        writer.begin_segment(Segments::PRD)?;
+        writer.write_all(
             &value_prediction_parameters
                 .iter()
                 .flat_map(|s| s.iter().flat_map(|x| x.to_le_bytes()))
                 .collect::<Vec<u8>>(),
-        );
+        )?;
 
-        serial.extend_from_slice(
+        writer.write_all(
             &width_prediction_parameters
                 .iter()
                 .flat_map(|s| s.iter().flat_map(|x| x.to_le_bytes()))
                 .collect::<Vec<u8>>(),
-        );
-
-        for ctx in ans_contexts {
-            serial.extend_from_slice(Segments::EHD);
-            serial.extend_from_slice(
-                &(ctx.max_freq_bits).to_le_bytes(),
-            );
-            serial.extend_from_slice(&(ctx.off_distribution_values.len().to_le_bytes()));
-            serial.extend_from_slice(
+        )?;
+        writer.end_segment()?;
+
+        let buckets = get_buckets(ans_contexts);
+        if buckets != DEFAULT_BUCKETS {
+            writer.begin_segment(Segments::CTX)?;
+            writer.write_all(&(buckets.len() as u32).to_le_bytes())?;
+            for (min_width, width) in buckets {
+                writer.write_all(&min_width.to_le_bytes())?;
+                writer.write_all(&width.to_le_bytes())?;
+            }
+            writer.end_segment()?;
+        }
+
+        let tables = match entropy_coder {
+            EntropyCoder::Rans => ans_contexts.as_slice(),
+            EntropyCoder::Binary => &[],
+        };
+        for ctx in tables.iter().filter(|ctx| !ctx.adaptive) {
+            if ctx.explicit_histogram {
+                writer.begin_segment(Segments::EHT)?;
+                writer.write_all(&ctx.histogram_bytes())?;
+                writer.end_segment()?;
+                continue;
+            }
+            writer.begin_segment(Segments::EHD)?;
+            writer.write_all(&(ctx.max_freq_bits).to_le_bytes())?;
+            writer.write_all(&(ctx.off_distribution_values.len() as u64).to_le_bytes())?;
+            writer.write_all(
                 &ctx.off_distribution_values
                     .iter()
                     .flat_map(|s| s.to_le_bytes())
                     .collect::<Vec<u8>>(),
-            );
+            )?;
+            writer.end_segment()?;
         }
-        serial.extend_from_slice(Segments::DAT);
-        serial.extend_from_slice(&data.len().to_le_bytes());
-        serial.extend(data);
-        serial.extend_from_slice(Segments::EOC);
+        writer.begin_segment(Segments::GIX)?;
+        writer.write_all(&(group_offsets.len() as u64).to_le_bytes())?;
+        writer.write_all(
+            &group_offsets
+                .iter()
+                .flat_map(|s| (*s as u64).to_le_bytes())
+                .collect::<Vec<u8>>(),
+        )?;
+        writer.end_segment()?;
+        writer.begin_segment(Segments::DAT)?;
+        writer.write_all(&(data.len() as u64).to_le_bytes())?;
+        writer.write_all(data)?;
+        writer.end_segment()?;
+        writer.begin_segment(Segments::EOC)?;
+        writer.end_segment()?;
         if i >= image.metadata.colorspace.num_channels() {
             break;
         }
     }
 
-    serial.extend_from_slice(Segments::EOI);
-    return Ok(serial);
+    writer.end_image()
+}
+
+pub enum SerializedImage {
+    Single(CompressedImage),
+    Tiled(TiledImage),
+    Sequence(SequenceImage),
+}
+
+pub fn decode(bytes: Vec<u8>, verify: bool) -> Result<SerializedImage, FriError> {
+    decode_from(bytes.as_slice(), verify)
 }
 
-pub fn decode(bytes: Vec<u8>) -> Result<CompressedImage, SerializeError> {
-    let mut offset = 0;
-    if &bytes[offset..offset + 4] != b"frif" {
-        return Err(SerializeError::InvalidSignature);
+/*
+ * Quantization steps only change within the coarse layers, so the matrix is
+ * stored as the LEB128 count of leading layers followed by their LEB128 steps.
+ * The remaining layers repeat the last stored step.
+ */
+fn encode_quantization_matrix(matrix: &[i32; QUANTIZATION_LAYERS]) -> Vec<u8> {
+    let last = matrix[QUANTIZATION_LAYERS - 1];
+    let stored = QUANTIZATION_LAYERS - matrix.iter().rev().take_while(|&&step| step == last).count() + 1;
+
+    let mut bytes = vec![];
+    utils::write_leb128(stored as u64, &mut bytes);
+    for &step in &matrix[..stored] {
+        utils::write_leb128(step as u64, &mut bytes);
     }
-    offset += 4;
+    bytes
+}
 
-    let height = u32::from_le_bytes(bytes[offset..offset + 4].try_into()?);
-    offset += 4;
+fn decode_quantization_matrix<R: Read>(
+    reader: &mut SegmentReader<R>,
+) -> Result<[i32; QUANTIZATION_LAYERS], FriError> {
+    if reader.version < COMPACT_QNT_VERSION {
+        let mut matrix = [1; QUANTIZATION_LAYERS];
+        for step in matrix.iter_mut() {
+            *step = reader.read_u32()? as i32;
+            if *step <= 0 || *step > MAX_QUANTIZATION_STEP {
+                return Err(FriError::MalformedSegment("QNT"));
+            }
+        }
+        return Ok(matrix);
+    }
 
-    let width = u32::from_le_bytes(bytes[offset..offset + 4].try_into()?);
-    offset += 4;
+    let stored = reader.read_leb128()?;
+    if stored == 0 || stored > QUANTIZATION_LAYERS as u64 {
+        return Err(FriError::MalformedSegment("QNT"));
+    }
 
-    let metadata = u32::from_le_bytes(bytes[offset..offset + 4].try_into()?);
-    offset += 4;
+    let mut matrix = [1; QUANTIZATION_LAYERS];
+    for i in 0..stored as usize {
+        let step = reader.read_leb128()?;
+        if step == 0 || step > MAX_QUANTIZATION_STEP as u64 {
+            return Err(FriError::MalformedSegment("QNT"));
+        }
+        matrix[i..].fill(step as i32);
+    }
+    Ok(matrix)
+}
 
-    let colorspace = ColorSpace::from_encoding((metadata >> 30 & 0b11) as u8)?;
-    let variant = FractalVariant::from_encoding((metadata >> 28 & 0b11) as u8)?;
+/*
+ * Reads a single or a tiled image, which one follows is known from the first
+ * segment after the header.
+ */
+pub fn decode_from<R: Read>(reader: R, verify: bool) -> Result<SerializedImage, FriError> {
+    let mut reader = SegmentReader::new(reader, verify);
+    let mut metadata = decode_header(&mut reader)?;
+    let mut marker = decode_embedded(&mut reader, &mut metadata.embedded)?;
+
+    if marker == Segments::TIL {
+        let tiles = deserialize_tiles(&mut reader, &metadata, marker)?;
+        return Ok(SerializedImage::Tiled(TiledImage { metadata, tiles }));
+    }
 
-    let channel_data = deserialize_channel_data(&bytes, offset)?;
+    if marker == Segments::FRM {
+        let frames = deserialize_frames(&mut reader, marker)?;
+        return Ok(SerializedImage::Sequence(SequenceImage { metadata, frames }));
+    }
+
+    let mut quantization_matrix = [1; QUANTIZATION_LAYERS];
+    if marker == Segments::QNT {
+        quantization_matrix = decode_quantization_matrix(&mut reader)?;
+        reader.end_segment()?;
+        marker = reader.read_marker()?;
+    }
 
-    Ok(CompressedImage {
-        metadata: ImageMetadata {
-            height,
-            width,
-            colorspace,
-            variant,
-        },
+    let mut group_size = 0;
+    if marker == Segments::FGR {
+        group_size = reader.read_u32()?;
+        reader.end_segment()?;
+        marker = reader.read_marker()?;
+    }
+
+    let channel_data = deserialize_channel_data(&mut reader, marker)?;
+
+    Ok(SerializedImage::Single(CompressedImage {
+        metadata,
         channel_data,
-    })
+        quantization_matrix,
+        group_size,
+        entropy_coder: reader.entropy_coder,
+    }))
+}
+
+/*
+ * Tiled images share the header of a single image, followed by TIL segments each
+ * holding the region of the tile and its complete serialized image. Tiles are
+ * written one by one as soon as they are encoded.
+ */
+pub fn encode_tile<W: Write>(tile: &EncodedTile, writer: &mut SegmentWriter<W>) -> Result<(), FriError> {
+    let EncodedTile { region, data } = tile;
+    writer.begin_segment(Segments::TIL)?;
+    writer.write_all(&region.x.to_le_bytes())?;
+    writer.write_all(&region.y.to_le_bytes())?;
+    writer.write_all(&region.width.to_le_bytes())?;
+    writer.write_all(&region.height.to_le_bytes())?;
+    writer.write_all(&(data.len() as u64).to_le_bytes())?;
+    writer.write_all(data)?;
+    writer.end_segment()
+}
+
+pub fn encode_end<W: Write>(writer: &mut SegmentWriter<W>) -> Result<(), FriError> {
+    writer.end_image()
+}
+
+// Frame is predicted from the previous one
+const FRAME_PREDICTED: u32 = 1;
+
+/*
+ * Sequences share the header of a single image, followed by FRM segments each
+ * holding the duration, flags and complete serialized image of a frame.
+ */
+pub fn encode_frame<W: Write>(frame: &EncodedFrame, writer: &mut SegmentWriter<W>) -> Result<(), FriError> {
+    let EncodedFrame { duration_ms, predicted, data } = frame;
+    let flags = if *predicted { FRAME_PREDICTED } else { 0 };
+    writer.begin_segment(Segments::FRM)?;
+    writer.write_all(&duration_ms.to_le_bytes())?;
+    writer.write_all(&flags.to_le_bytes())?;
+    writer.write_all(&(data.len() as u64).to_le_bytes())?;
+    writer.write_all(data)?;
+    writer.end_segment()
+}
+
+fn deserialize_frames<R: Read>(
+    reader: &mut SegmentReader<R>,
+    mut marker: [u8; 2],
+) -> Result<Vec<EncodedFrame>, FriError> {
+    let mut frames = vec![];
+    loop {
+        match &marker[..] {
+            Segments::FRM => {
+                let duration_ms = reader.read_u32()?;
+                let flags = reader.read_u32()?;
+                let data_len = reader.read_u64()?;
+                let data = reader.read_bytes(data_len)?;
+                if flags & !FRAME_PREDICTED != 0 || (frames.is_empty() && flags & FRAME_PREDICTED != 0) {
+                    return Err(FriError::MalformedSegment("FRM"));
+                }
+
+                frames.push(EncodedFrame {
+                    duration_ms,
+                    predicted: flags & FRAME_PREDICTED != 0,
+                    data,
+                });
+            }
+            Segments::EOI => {
+                reader.end_image()?;
+                return Ok(frames);
+            }
+            _other => return Err(FriError::MalformedSegment("unknown")),
+        }
+        reader.end_segment()?;
+        marker = reader.read_marker()?;
+    }
+}
+
+fn deserialize_tiles<R: Read>(
+    reader: &mut SegmentReader<R>,
+    metadata: &ImageMetadata,
+    mut marker: [u8; 2],
+) -> Result<Vec<EncodedTile>, FriError> {
+    let mut tiles = vec![];
+    loop {
+        match &marker[..] {
+            Segments::TIL => {
+                let mut fields = [0u32; 4];
+                for field in fields.iter_mut() {
+                    *field = reader.read_u32()?;
+                }
+                let [x, y, width, height] = fields;
+                if x as u64 + width as u64 > metadata.width as u64
+                    || y as u64 + height as u64 > metadata.height as u64
+                {
+                    return Err(FriError::MalformedSegment("TIL"));
+                }
+
+                let data_len = reader.read_u64()?;
+                let data = reader.read_bytes(data_len)?;
+
+                tiles.push(EncodedTile {
+                    region: Region { x, y, width, height },
+                    data,
+                });
+            }
+            Segments::EOI => {
+                reader.end_image()?;
+                return Ok(tiles);
+            }
+            _other => return Err(FriError::MalformedSegment("unknown")),
+        }
+        reader.end_segment()?;
+        marker = reader.read_marker()?;
+    }
 }
 
-fn deserialize_channel_data(
-    bytes: &Vec<u8>,
-    mut offset: usize,
-) -> Result<[Option<ChannelData>; 3], SerializeError> {
+fn deserialize_channel_data<R: Read>(
+    reader: &mut SegmentReader<R>,
+    mut marker: [u8; 2],
+) -> Result<[Option<ChannelData>; 3], FriError> {
     let mut channel_data = [None, None, None];
     let mut ans_contexts: Vec<AnsContext> = vec![];
+    let mut buckets = DEFAULT_BUCKETS.to_vec();
     let mut encoded_bytes: Vec<u8> = vec![];
+    let mut group_offsets: Vec<usize> = vec![0];
     let mut value_prediction_parameters: Vec<[f32; 6]> = vec![[0.; 6]; 3];
     let mut width_prediction_parameters: Vec<[f32; 6]> = vec![[0.; 6]; 3];
     let mut i = 0;
     loop {
-        match &bytes[offset..offset + 2] {
+        match &marker[..] {
             Segments::PRD => {
-                offset += 2;
-
-                value_prediction_parameters[0] = bytes[offset..offset + 6 * 4]
-                    .chunks_exact(4)
-                    .map(|e| f32::from_le_bytes(e.try_into().unwrap()))
-                    .collect::<Vec<_>>()
-                    .try_into()
-                    .unwrap();
-                offset += 6 * 4;
-
-                value_prediction_parameters[1] = bytes[offset..offset + 6 * 4]
-                    .chunks_exact(4)
-                    .map(|e| f32::from_le_bytes(e.try_into().unwrap()))
-                    .collect::<Vec<_>>()
-                    .try_into()
-                    .unwrap();
-                offset += 6 * 4;
-
-                value_prediction_parameters[2] = bytes[offset..offset + 6 * 4]
-                    .chunks_exact(4)
-                    .map(|e| f32::from_le_bytes(e.try_into().unwrap()))
-                    .collect::<Vec<_>>()
-                    .try_into()
-                    .unwrap();
-                offset += 6 * 4;
-
-                width_prediction_parameters[0] = bytes[offset..offset + 6 * 4]
-                    .chunks_exact(4)
-                    .map(|e| f32::from_le_bytes(e.try_into().unwrap()))
-                    .collect::<Vec<_>>()
-                    .try_into()
-                    .unwrap();
-                offset += 6 * 4;
-
-                width_prediction_parameters[1] = bytes[offset..offset + 6 * 4]
-                    .chunks_exact(4)
-                    .map(|e| f32::from_le_bytes(e.try_into().unwrap()))
-                    .collect::<Vec<_>>()
-                    .try_into()
-                    .unwrap();
-                offset += 6 * 4;
-
-                width_prediction_parameters[2] = bytes[offset..offset + 6 * 4]
-                    .chunks_exact(4)
-                    .map(|e| f32::from_le_bytes(e.try_into().unwrap()))
-                    .collect::<Vec<_>>()
-                    .try_into()
-                    .unwrap();
-                offset += 6 * 4;
+                for parameters in value_prediction_parameters
+                    .iter_mut()
+                    .chain(width_prediction_parameters.iter_mut())
+                {
+                    for parameter in parameters.iter_mut() {
+                        *parameter = reader.read_f32()?;
+                    }
+                }
+            }
+            Segments::CTX => {
+                let count = reader.read_u32()? as usize;
+                if count == 0 || count > MAX_CONTEXTS || !ans_contexts.is_empty() {
+                    return Err(FriError::MalformedSegment("CTX"));
+                }
+                buckets.clear();
+                for _ in 0..count {
+                    let min_width = reader.read_u32()?;
+                    let width = reader.read_f32()?;
+                    let ordered = match buckets.last() {
+                        Some(&(previous, _)) => min_width > previous,
+                        None => min_width == 0,
+                    };
+                    if !ordered || !width.is_finite() || width < MIN_CONTEXT_WIDTH {
+                        return Err(FriError::MalformedSegment("CTX"));
+                    }
+                    buckets.push((min_width, width));
+                }
             }
             Segments::EHD => {
-                offset += 2;
-
-                let max_freq_bits = u32::from_le_bytes(bytes[offset..offset + 4].try_into()?);
-                offset += 4;
-
-                let off_distribution_len = usize::from_le_bytes(bytes[offset..offset + 8].try_into()?);
-                offset += 8;
-
-                let off_distribution_vals: Vec<u16> = bytes[offset..offset + off_distribution_len as usize * 2]
+                let max_freq_bits = reader.read_u32()?;
+                let off_distribution_len = reader.read_u64()?;
+                if max_freq_bits > MAX_FREQ_BITS
+                    || off_distribution_len > ALPHABET_SIZE as u64
+                    || ans_contexts.len() >= buckets.len()
+                {
+                    return Err(FriError::MalformedSegment("EHD"));
+                }
+                let off_distribution_vals: Vec<u16> = reader
+                    .read_bytes(off_distribution_len * 2)?
                     .chunks_exact(2)
-                    .map(|e| u16::from_le_bytes(e.try_into().unwrap()))
+                    .map(|e| u16::from_le_bytes([e[0], e[1]]))
                     .collect();
 
-                offset += off_distribution_len as usize * 2;
-
-                let mut context = AnsContext::new();
+                let (min_width, width) = buckets[ans_contexts.len()];
+                let mut context = AnsContext::for_bucket(min_width, width);
 
                 context.max_freq_bits = max_freq_bits;
                 context.off_distribution_values = off_distribution_vals;
                 //context.freqs = (*freqs.into_boxed_slice()).try_into().unwrap();
-                context.finalize_context(true, ans_contexts.len());
+                context.finalize_context(true);
                 ans_contexts.push(context)
             }
-            Segments::DAT => {
-                offset += 2;
-
-                let data_len = u64::from_le_bytes(bytes[offset..offset + 8].try_into()?) as usize;
-                offset += 8;
-
-                let data = bytes[offset..offset + data_len as usize].to_vec();
-                offset += data_len;
+            Segments::EHT => {
+                let [max_freq_bits] = reader.read_array()?;
+                let used = reader.read_leb128()?;
+                if !(MIN_HISTOGRAM_BITS..=MAX_HISTOGRAM_BITS).contains(&(max_freq_bits as u32))
+                    || used > ALPHABET_SIZE as u64
+                    || ans_contexts.len() >= buckets.len()
+                {
+                    return Err(FriError::MalformedSegment("EHT"));
+                }
 
-                encoded_bytes = data;
+                let (min_width, width) = buckets[ans_contexts.len()];
+                let mut context = AnsContext::for_bucket(min_width, width);
+                context.explicit_histogram = true;
+                context.max_freq_bits = max_freq_bits as u32;
+                let mut token = 0;
+                while token < used as usize {
+                    let freq = reader.read_leb128()?;
+                    if freq > 1 << max_freq_bits {
+                        return Err(FriError::MalformedSegment("EHT"));
+                    }
+                    context.freqs[token] = freq as u32;
+                    token += 1;
+                    if freq == 0 {
+                        token = token.saturating_add(reader.read_leb128()? as usize);
+                    }
+                }
+                if token > used as usize
+                    || context.freqs.iter().map(|&freq| freq as u64).sum::<u64>() != 1 << max_freq_bits
+                {
+                    return Err(FriError::MalformedSegment("EHT"));
+                }
+                context.finalize_context(true);
+                ans_contexts.push(context)
+            }
+            Segments::GIX => {
+                let group_count = reader.read_u64()?;
+                let index_len = group_count.checked_mul(8).ok_or(FriError::MalformedSegment("GIX"))?;
+                group_offsets = reader
+                    .read_bytes(index_len)?
+                    .chunks_exact(8)
+                    .map(|e| u64::from_le_bytes(e.try_into().unwrap()) as usize)
+                    .collect();
+            }
+            Segments::DAT => {
+                let data_len = reader.read_u64()?;
+                encoded_bytes = reader.read_bytes(data_len)?;
             }
             Segments::EOC => {
-                offset += 2;
-
+                if i >= channel_data.len() {
+                    return Err(FriError::MalformedSegment("EOC"));
+                }
+                if reader.adaptive || reader.entropy_coder == EntropyCoder::Binary {
+                    if !ans_contexts.is_empty() {
+                        return Err(FriError::MalformedSegment("EHD"));
+                    }
+                    ans_contexts = buckets
+                        .iter()
+                        .map(|&(min_width, width)| match reader.adaptive {
+                            true => AnsContext::adaptive_prior(min_width, width),
+                            false => AnsContext::for_bucket(min_width, width),
+                        })
+                        .collect();
+                }
+                if ans_contexts.len() != buckets.len() {
+                    return Err(FriError::MalformedSegment("EHD"));
+                }
+                buckets = DEFAULT_BUCKETS.to_vec();
                 channel_data[i] = Some(ChannelData {
                     ans_contexts,
                     data: encoded_bytes,
+                    group_offsets,
                     value_prediction_parameters,
                     width_prediction_parameters,
                 });
@@ -259,10 +905,216 @@ fn deserialize_channel_data(
                 width_prediction_parameters = vec![[0.; 6]; 3];
                 ans_contexts = vec![];
                 encoded_bytes = vec![];
+                group_offsets = vec![0];
                 i += 1;
             }
-            Segments::EOI => return Ok(channel_data),
-            _other => return Err(SerializeError::MalformedImageBytes),
+            Segments::EOI => {
+                reader.end_image()?;
+                return Ok(channel_data);
+            }
+            _other => return Err(FriError::MalformedSegment("unknown")),
+        }
+        reader.end_segment()?;
+        marker = reader.read_marker()?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /*
+     * Image with made up channel streams, which the serializer stores without
+     * looking into them.
+     */
+    fn compressed() -> CompressedImage {
+        let channel = |seed: u8| ChannelData {
+            ans_contexts: DEFAULT_BUCKETS
+                .iter()
+                .map(|&(min_width, width)| {
+                    let mut context = AnsContext::for_bucket(min_width, width);
+                    context.finalize_context(true);
+                    context
+                })
+                .collect(),
+            data: (0..40).map(|i| seed.wrapping_mul(i + 1)).collect(),
+            group_offsets: vec![0, 24],
+            value_prediction_parameters: vec![[seed as f32; 6]; 3],
+            width_prediction_parameters: vec![[0.5; 6]; 3],
+        };
+        CompressedImage {
+            metadata: ImageMetadata::new(23, 37),
+            channel_data: [Some(channel(3)), Some(channel(5)), Some(channel(7))],
+            quantization_matrix: [1; QUANTIZATION_LAYERS],
+            group_size: 256,
+            entropy_coder: EntropyCoder::Rans,
+        }
+    }
+
+    fn single(image: SerializedImage) -> CompressedImage {
+        match image {
+            SerializedImage::Single(image) => image,
+            _ => panic!("expected a single image"),
+        }
+    }
+
+    fn assert_same_channels(decoded: &CompressedImage, expected: &CompressedImage) {
+        for (decoded, expected) in decoded.channel_data.iter().zip(&expected.channel_data) {
+            let (decoded, expected) = (decoded.as_ref().unwrap(), expected.as_ref().unwrap());
+            assert_eq!(decoded.data, expected.data);
+            assert_eq!(decoded.group_offsets, expected.group_offsets);
+            assert_eq!(decoded.value_prediction_parameters, expected.value_prediction_parameters);
+            assert_eq!(decoded.width_prediction_parameters, expected.width_prediction_parameters);
+            assert_eq!(get_buckets(&decoded.ans_contexts), get_buckets(&expected.ans_contexts));
+        }
+    }
+
+    #[test]
+    fn checksums_name_the_corrupted_segment() {
+        let encoded = encode(compressed(), true).unwrap();
+        let unchecked = encode(compressed(), false).unwrap();
+        assert!(unchecked.len() < encoded.len());
+        assert_same_channels(&single(decode(unchecked, true).unwrap()), &compressed());
+        assert_same_channels(&single(decode(encoded.clone(), true).unwrap()), &compressed());
+
+        // The last DAT payload is followed by its CRC32, an empty EOC segment and the end of image
+        let mut tail = vec![];
+        let mut writer = SegmentWriter::new(&mut tail, true);
+        writer.begin_segment(Segments::EOC).unwrap();
+        writer.end_segment().unwrap();
+        writer.end_image().unwrap();
+        drop(writer);
+        let position = encoded.len() - tail.len() - std::mem::size_of::<u32>() - 1;
+
+        let mut corrupted = encoded.clone();
+        corrupted[position] ^= 1;
+        assert!(matches!(decode(corrupted.clone(), true), Err(FriError::ChecksumMismatch("DAT"))));
+        let mut expected = compressed();
+        *expected.channel_data[2].as_mut().unwrap().data.last_mut().unwrap() ^= 1;
+        assert_same_channels(&single(decode(corrupted, false).unwrap()), &expected);
+
+        // Header fields are only covered by the checksum of the whole file
+        let mut corrupted = encoded;
+        corrupted[b"frif".len()] ^= 1;
+        assert!(matches!(decode(corrupted, true), Err(FriError::ChecksumMismatch("whole file"))));
+    }
+
+    #[test]
+    fn embedded_metadata_round_trip() {
+        let embedded = EmbeddedMetadata {
+            icc_profile: Some(vec![1, 2, 3]),
+            exif: None,
+            xmp: Some(b"<x:xmpmeta/>".to_vec()),
+        };
+
+        let mut image = compressed();
+        image.metadata.embedded = embedded.clone();
+        let decoded = single(decode(encode(image, true).unwrap(), true).unwrap());
+        assert_eq!(decoded.metadata.embedded, embedded);
+        assert_same_channels(&decoded, &compressed());
+
+        // Tiles share the metadata stored once in the outer header
+        let mut metadata = compressed().metadata;
+        metadata.embedded = embedded.clone();
+        let tile = EncodedTile {
+            region: Region { x: 0, y: 0, width: 16, height: 23 },
+            data: encode(compressed(), true).unwrap(),
+        };
+        let mut tiled = vec![];
+        let mut writer = SegmentWriter::new(&mut tiled, true);
+        encode_header(&mut writer, &metadata).unwrap();
+        encode_tile(&tile, &mut writer).unwrap();
+        encode_end(&mut writer).unwrap();
+        drop(writer);
+        match decode(tiled, true).unwrap() {
+            SerializedImage::Tiled(image) => {
+                assert_eq!(image.metadata.embedded, embedded);
+                assert_eq!(image.tiles[0].data, tile.data);
+            }
+            _ => panic!("expected a tiled image"),
+        }
+    }
+
+    // Length of the header of compressed(), which ends with the header word
+    fn header_len() -> usize {
+        let mut header = vec![];
+        encode_header(&mut SegmentWriter::new(&mut header, false), &compressed().metadata).unwrap();
+        header.len()
+    }
+
+    #[test]
+    fn quantization_matrix_round_trip() {
+        use crate::encoder::EncoderQuality;
+        use crate::stages::quantization::get_quantization_matrix;
+
+        for quality in [EncoderQuality::Lossless, EncoderQuality::High, EncoderQuality::Low] {
+            let expected = get_quantization_matrix(&quality, 1.);
+            let mut image = compressed();
+            image.quantization_matrix = expected;
+            assert_eq!(single(decode(encode(image, true).unwrap(), true).unwrap()).quantization_matrix, expected);
+        }
+
+        // Streams before version 3 store all steps as u32
+        let expected = get_quantization_matrix(&EncoderQuality::Low, 1.);
+        let mut legacy = encode(compressed(), false).unwrap();
+        let header_len = header_len();
+        legacy[header_len - 4] = COMPACT_QNT_VERSION - 1;
+        let mut segment = Segments::QNT.to_vec();
+        utils::write_leb128(4 * QUANTIZATION_LAYERS as u64, &mut segment);
+        segment.extend(expected.iter().flat_map(|step| step.to_le_bytes()));
+        legacy.splice(header_len..header_len, segment);
+        assert_eq!(single(decode(legacy, true).unwrap()).quantization_matrix, expected);
+    }
+
+    #[test]
+    fn unknown_segments_are_skipped() {
+        let encoded = encode(compressed(), false).unwrap();
+        let header_len = header_len();
+
+        // Segment of a newer encoder right after the header, holding three bytes
+        let mut extended = encoded.clone();
+        extended.splice(header_len..header_len, [0xFF, 0xC0, 3, 1, 2, 3]);
+        assert_same_channels(&single(decode(extended, true).unwrap()), &compressed());
+
+        let mut newer = encoded;
+        newer[header_len - 4] = FORMAT_VERSION + 1;
+        assert!(matches!(decode(newer, true), Err(FriError::Unsupported("format version"))));
+    }
+
+    #[test]
+    fn version_0_streams_are_read() {
+        use crate::encoder::EncoderQuality;
+        use crate::stages::quantization::get_quantization_matrix;
+
+        // Version 0 segments are not prefixed by their lengths and QNT holds all steps as u32
+        let encoded = encode(compressed(), false).unwrap();
+        let header_len = header_len();
+        let mut legacy = encoded[..header_len].to_vec();
+        legacy[header_len - 4] = 0;
+
+        let matrix = get_quantization_matrix(&EncoderQuality::Low, 1.);
+        legacy.extend(Segments::QNT);
+        legacy.extend(matrix.iter().flat_map(|step| step.to_le_bytes()));
+
+        let mut position = header_len;
+        while position < encoded.len() {
+            legacy.extend(&encoded[position..position + 2]);
+            position += 2;
+            let mut len = 0;
+            for shift in (0..).step_by(7) {
+                let byte = encoded[position];
+                position += 1;
+                len |= ((byte & 0x7F) as usize) << shift;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            legacy.extend(&encoded[position..position + len]);
+            position += len;
         }
+
+        let decoded = single(decode(legacy, true).unwrap());
+        assert_eq!(decoded.quantization_matrix, matrix);
+        assert_same_channels(&decoded, &compressed());
     }
 }
@@ -0,0 +1,124 @@
+use rayon::prelude::*;
+
+use crate::stages::entropy_coding::MAX_COEFFICIENT;
+use crate::stages::wavelet_transform::WaveletImage;
+
+/*
+ * Frames of a sequence share the fractal lattice, so every coefficient has its
+ * counterpart in the previous frame. Differences are taken on quantized
+ * coefficients which the decoder reconstructs exactly, so errors never drift
+ * from frame to frame. The differences are then coded as any other image, with
+ * spatial prediction and context buckets of the prediction stage.
+ */
+fn combine(
+    mut image: WaveletImage,
+    previous: &WaveletImage,
+    op: fn(i32, i32) -> i32,
+) -> Result<WaveletImage, String> {
+    if image.fractal_lattice.len() != previous.fractal_lattice.len() {
+        return Err(String::from("Frame lattice differs from the previous frame"));
+    }
+
+    image
+        .fractal_lattice
+        .par_iter_mut()
+        .try_for_each(|(position, fractal)| -> Result<(), String> {
+            let previous_fractal = previous
+                .fractal_lattice
+                .get(position)
+                .ok_or_else(|| String::from("Frame lattice differs from the previous frame"))?;
+            for (channel, previous_channel) in fractal.coefficients.iter_mut().zip(&previous_fractal.coefficients) {
+                for (coef, previous_coef) in channel.iter_mut().zip(previous_channel) {
+                    if let (Some(coef), Some(previous_coef)) = (coef, previous_coef) {
+                        *coef = op(*coef, *previous_coef);
+                    }
+                }
+            }
+            Ok(())
+        })?;
+
+    Ok(image)
+}
+
+pub fn encode(image: WaveletImage, previous: &WaveletImage) -> Result<WaveletImage, String> {
+    combine(image, previous, |coef, previous_coef| coef - previous_coef)
+}
+
+pub fn decode(image: WaveletImage, previous: &WaveletImage) -> Result<WaveletImage, String> {
+    combine(image, previous, |coef, previous_coef| {
+        (coef as i64 + previous_coef as i64).clamp(-MAX_COEFFICIENT, MAX_COEFFICIENT) as i32
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::images::ImageMetadata;
+
+    #[test]
+    fn decode_inverts_encode() {
+        let previous = WaveletImage::from_metadata(ImageMetadata::new(20, 30));
+        let mut image = previous.clone();
+        for (i, fractal) in image.fractal_lattice.values_mut().enumerate() {
+            for coef in fractal.coefficients[0].iter_mut().flatten() {
+                *coef = i as i32 % 7 - 3;
+            }
+        }
+        let mut previous = previous;
+        for fractal in previous.fractal_lattice.values_mut() {
+            for coef in fractal.coefficients[1].iter_mut().flatten() {
+                *coef = 5;
+            }
+        }
+
+        let residual = encode(image.clone(), &previous).unwrap();
+        let restored = decode(residual, &previous).unwrap();
+        for (position, fractal) in &image.fractal_lattice {
+            assert_eq!(fractal.coefficients, restored.fractal_lattice[position].coefficients);
+        }
+    }
+
+    #[test]
+    fn sequence_round_trip() {
+        use crate::decoder::{DecoderOpts, FRIDecoder};
+        use crate::encoder::{EncoderOpts, EncoderQuality, FRIEncoder};
+        use crate::images::{Frame, RasterImage, Region};
+        use crate::test_images::{self, noisy_gradient, raster};
+
+        // Noisy scene panning two pixels per frame, so residuals stay nonzero
+        let (width, height) = (37, 23);
+        let scene = raster(width + 8, height, noisy_gradient(width + 8, height, 31));
+        let frames: Vec<Vec<u8>> = (0..4)
+            .map(|t| scene.crop(&Region { x: 2 * t, y: 0, width, height }).unwrap().data)
+            .collect();
+        let to_frames = || {
+            frames.iter().enumerate().map(|(t, data)| Frame {
+                image: RasterImage { metadata: ImageMetadata::new(height, width), data: data.clone() },
+                duration_ms: 40 * t as u32,
+            })
+        };
+
+        for quality in [EncoderQuality::Lossless, EncoderQuality::Low] {
+            let opts = || EncoderOpts { quality, keyframe_interval: 2, ..Default::default() };
+            let mut encoded = vec![];
+            FRIEncoder::new(opts()).unwrap().encode_sequence(to_frames(), &mut encoded).unwrap();
+
+            let decoded: Vec<Frame> = FRIDecoder::new(DecoderOpts::default()).unwrap()
+                .decode_frames(encoded.as_slice())
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(decoded.len(), frames.len());
+
+            // Differences are taken after quantization, so every frame matches its standalone coding
+            for (t, (frame, data)) in decoded.iter().zip(&frames).enumerate() {
+                let expected = test_images::round_trip(opts(), width, height, data);
+                assert_eq!(frame.image.data, expected.data);
+                assert_eq!(frame.duration_ms, 40 * t as u32);
+                if let EncoderQuality::Lossless = quality {
+                    assert_eq!(&frame.image.data, data);
+                }
+            }
+        }
+    }
+}
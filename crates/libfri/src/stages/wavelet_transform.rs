@@ -3,13 +3,15 @@ use std::collections::{HashMap, HashSet, VecDeque};
 use std::vec;
 
 use crate::encoder::EncoderOpts;
-use crate::fractal::{self, CENTERS, LITERALS};
-use crate::images::{ImageMetadata, RasterImage};
+use crate::fractal;
+use crate::images::{FractalVariant, ImageMetadata, RasterImage, Region};
+use crate::stages::quantization::QUANTIZATION_LAYERS;
 use crate::utils;
 
 use itertools::Position;
 use num::complex::ComplexFloat;
 use num::{Complex, Float};
+use rayon::prelude::*;
 
 fn try_apply<T: Copy>(
     first: Option<T>,
@@ -25,7 +27,7 @@ fn try_apply<T: Copy>(
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Fractal {
     pub depth: u8,
     pub center: Complex<i32>,
@@ -34,12 +36,22 @@ pub struct Fractal {
     pub values: [Vec<Option<i32>>; 3],
     pub position_map: Vec<HashMap<Complex<i32>, usize>>,
     pub image_positions: Vec<Complex<i32>>,
+    pub group: usize,
+    pub variant: FractalVariant,
 }
 
-const BASE_FRAC_DEPTH: u8 = 9;
+pub const BASE_FRAC_DEPTH: u8 = 9;
+
+/*
+ * Side of the square cell of fractal centers coded as one independent stream.
+ * Context modeling never crosses a group boundary, so a region of the image can
+ * be decoded by entropy decoding only the groups that intersect it.
+ */
+pub const FRACTAL_GROUP_SIZE: u32 = 256;
 
 impl Fractal {
-    fn new(depth: u8, center: Complex<i32>) -> Self {
+    fn new(depth: u8, center: Complex<i32>, variant: FractalVariant) -> Self {
+        let literals = variant.get_literals();
         let mut position_map = vec![HashMap::new(); depth as usize];
         let mut image_positions = vec![Complex::<i32>::new(0, 0); 1 << (depth + 1)];
         image_positions[0] = center;
@@ -49,7 +61,7 @@ impl Fractal {
                 position_map[level as usize].insert(image_positions[pos], pos);
                 image_positions[2 * pos] = image_positions[pos];
                 image_positions[2 * pos + 1] =
-                    image_positions[pos] + LITERALS[(depth - level - 1) as usize];
+                    image_positions[pos] + literals[(depth - level - 1) as usize];
             }
         }
 
@@ -65,10 +77,27 @@ impl Fractal {
             position_map,
             image_positions,
             values: [vec![], vec![], vec![]],
+            group: 0,
+            variant,
         }
     }
 
-    fn get_nearby_vectors(depth: u8) -> [Complex<i32>; 6] {
+    fn get_nearby_vectors(variant: FractalVariant, depth: u8) -> [Complex<i32>; 6] {
+        if variant == FractalVariant::Twindragon {
+            // Every twindragon touches the six tiles shifted by base^depth * {±1, ±i, ±(1 + i)}
+            let zl = variant.get_literals()[depth as usize];
+            let zmd = zl * Complex::new(0, -1);
+            return [zl, zl - zmd, -zmd, -zl, zmd - zl, zmd];
+        }
+
+        if variant == FractalVariant::Boxes {
+            // Left, up and up-right blocks form the causal part of the neighbourhood
+            let (width, height) = (1 << ((depth + 1) / 2), 1 << (depth / 2));
+            let zl = Complex::new(width, -height);
+            let zmd = Complex::new(0, -height);
+            return [zl, zl - zmd, -zmd, -zl, zmd - zl, zmd];
+        }
+
         if depth == 1 {
             let zl = Complex::new(-1, 1);
             let zmd = Complex::new(0, 2);
@@ -82,43 +111,48 @@ impl Fractal {
             let zmd = Complex::new(-1, -3);
             return [zl, zl - zmd, -zmd, -zl, zmd - zl, zmd];
         } else {
-            let zl = LITERALS[depth as usize];
-            let zmd = LITERALS[depth as usize + 1] + zl;
+            let literals = variant.get_literals();
+            let zl = literals[depth as usize];
+            let zmd = literals[depth as usize + 1] + zl;
 
             return [zl, zl - zmd, -zmd, -zl, zmd - zl, zmd];
         }
     }
 
     pub fn get_neighbour_locations(&self) -> [Complex<i32>; 6] {
-        let vectors = Self::get_nearby_vectors(self.depth);
+        let vectors = Self::get_nearby_vectors(self.variant, self.depth);
         return vectors.map(|x| self.center + x).try_into().unwrap();
     }
 
     pub fn get_left(
+        &self,
         center: Complex<i32>,
         depth: u8,
         global_position_map: &Vec<HashMap<Complex<i32>, Complex<i32>>>,
     ) -> Complex<i32> {
-        let vectors = Self::get_nearby_vectors(depth);
+        let vectors = Self::get_nearby_vectors(self.variant, depth);
         center + vectors[4]
     }
 
     pub fn get_right(
+        &self,
         center: Complex<i32>,
         depth: u8,
         global_position_map: &Vec<HashMap<Complex<i32>, Complex<i32>>>,
     ) -> Complex<i32> {
-        let vectors = Self::get_nearby_vectors(depth);
+        let vectors = Self::get_nearby_vectors(self.variant, depth);
         center + vectors[1]
     }
 
     pub fn get_down_left(
+        &self,
         center: Complex<i32>,
         depth: u8,
         global_position_map: &Vec<HashMap<Complex<i32>, Complex<i32>>>,
     ) -> Complex<i32> {
-        let vectors = Self::get_nearby_vectors(depth);
-        if depth == 2
+        let vectors = Self::get_nearby_vectors(self.variant, depth);
+        if self.variant == FractalVariant::TameTwindragon
+            && depth == 2
             && !global_position_map[depth as usize].contains_key(&(center + vectors[3]))
             && global_position_map[depth as usize].contains_key(&(center + Complex::new(1, 1)))
         {
@@ -129,12 +163,14 @@ impl Fractal {
     }
 
     pub fn get_down_right(
+        &self,
         center: Complex<i32>,
         depth: u8,
         global_position_map: &Vec<HashMap<Complex<i32>, Complex<i32>>>,
     ) -> Complex<i32> {
-        let vectors = Self::get_nearby_vectors(depth);
-        if depth == 2
+        let vectors = Self::get_nearby_vectors(self.variant, depth);
+        if self.variant == FractalVariant::TameTwindragon
+            && depth == 2
             && !global_position_map[depth as usize].contains_key(&(center + vectors[3]))
             && global_position_map[depth as usize].contains_key(&(center + Complex::new(1, 1)))
         {
@@ -145,12 +181,14 @@ impl Fractal {
     }
 
     pub fn get_up_right(
+        &self,
         center: Complex<i32>,
         depth: u8,
         global_position_map: &Vec<HashMap<Complex<i32>, Complex<i32>>>,
     ) -> Complex<i32> {
-        let vectors = Self::get_nearby_vectors(depth);
-        if depth == 2
+        let vectors = Self::get_nearby_vectors(self.variant, depth);
+        if self.variant == FractalVariant::TameTwindragon
+            && depth == 2
             && !global_position_map[depth as usize].contains_key(&(center + vectors[0]))
             && global_position_map[depth as usize].contains_key(&(center + Complex::new(-1, -1)))
         {
@@ -161,12 +199,14 @@ impl Fractal {
     }
 
     pub fn get_up_left(
+        &self,
         center: Complex<i32>,
         depth: u8,
         global_position_map: &Vec<HashMap<Complex<i32>, Complex<i32>>>,
     ) -> Complex<i32> {
-        let vectors = Self::get_nearby_vectors(depth);
-        if depth == 2
+        let vectors = Self::get_nearby_vectors(self.variant, depth);
+        if self.variant == FractalVariant::TameTwindragon
+            && depth == 2
             && !global_position_map[depth as usize].contains_key(&(center + vectors[0]))
             && global_position_map[depth as usize].contains_key(&(center + Complex::new(-1, -1)))
         {
@@ -176,6 +216,12 @@ impl Fractal {
         }
     }
 
+    pub fn intersects(&self, region: &Region) -> bool {
+        self.image_positions[1 << self.depth..]
+            .iter()
+            .any(|pos| region.contains(pos.re, pos.im))
+    }
+
     fn extract_coefficients(&mut self, raster_image: &RasterImage, depth: u8) {
         let mut coefficients = [
             vec![None; 1 << depth],
@@ -225,15 +271,6 @@ impl Fractal {
     }
 }
 
-fn calculate_depth_center(img_w: u32, img_h: u32) -> (u8, Complex<i32>) {
-    let ((_, _), center, depth) = CENTERS
-        .into_iter()
-        .find(|&((w, h), _, _)| w >= (img_w as i32) && h >= (img_h as i32))
-        .unwrap();
-
-    return (depth, center);
-}
-
 fn color_pixel(raster: &mut RasterImage, key: &Complex<i32>, color: i32, channel: usize) {
     raster.set_pixel(key.re, key.im, color, channel);
     //raster.set_pixel(key.re+1, key.im, color, 0);
@@ -305,19 +342,29 @@ fn get_hf_context_bucket(
         .collect();
 }
 impl RasterImage {
-    pub fn from_wavelet(wavelet_image: WaveletImage) -> RasterImage {
+    pub fn from_wavelet(wavelet_image: WaveletImage, region: Option<&Region>) -> RasterImage {
         let mut raster = RasterImage {
             data: vec![
                 0;
                 wavelet_image.metadata.height as usize
                     * wavelet_image.metadata.width as usize
-                    * wavelet_image.metadata.colorspace.num_channels()
+                    * wavelet_image.metadata.colorspace.bytes_per_pixel()
             ],
             metadata: wavelet_image.metadata,
         };
 
-        for (_center, fractal) in wavelet_image.fractal_lattice.iter() {
-            raster.extract_values(&fractal);
+        // Fractals cover disjoint pixels, so they are reconstructed in parallel and
+        // only written into the raster afterwards
+        let num_channels = raster.metadata.colorspace.num_channels();
+        let bounds = (raster.metadata.width, raster.metadata.height);
+        let fractal_values: Vec<Vec<(Complex<i32>, usize, i32)>> = wavelet_image
+            .fractal_lattice
+            .par_iter()
+            .filter(|(_, fractal)| region.map_or(true, |region| fractal.intersects(region)))
+            .map(|(_, fractal)| Self::extract_values(fractal, num_channels, fractal.depth, bounds))
+            .collect();
+        for (position, channel, value) in fractal_values.into_iter().flatten() {
+            raster.set_pixel(position.re, position.im, value, channel);
         }
 
         if false {
@@ -355,37 +402,119 @@ impl RasterImage {
         return raster;
     }
 
-    fn extract_values(&mut self, fractal: &Fractal) {
-        for channel in 0..self.metadata.colorspace.num_channels() {
-            let mut low_pass_values = vec![0; 1 << fractal.depth];
-            low_pass_values[1] = fractal.coefficients[channel][0].unwrap();
+    /*
+     * Reconstructs only the first levels of every fractal, the subtrees below
+     * them hold their low-pass value. Every two levels left out halve both sides
+     * of the raster, whose pixels are the means of the blocks of pixels they cover.
+     */
+    pub fn preview_from_wavelet(
+        wavelet_image: WaveletImage,
+        max_level: u8,
+        region: Option<&Region>,
+    ) -> RasterImage {
+        let depth = wavelet_image.fractal_lattice.values().next().map_or(0, |fractal| fractal.depth);
+        let last_level = max_level.saturating_add(1).min(depth);
+        let scale = get_preview_scale(depth, max_level);
+        let (full_width, full_height) = (wavelet_image.metadata.width, wavelet_image.metadata.height);
+        let (width, height) = (full_width.div_ceil(scale), full_height.div_ceil(scale));
+        let num_channels = wavelet_image.metadata.colorspace.num_channels();
+
+        let fractal_values: Vec<Vec<(Complex<i32>, usize, i32)>> = wavelet_image
+            .fractal_lattice
+            .par_iter()
+            .filter(|(_, fractal)| region.map_or(true, |region| fractal.intersects(region)))
+            .map(|(_, fractal)| {
+                Self::extract_values(fractal, num_channels, last_level, (full_width, full_height))
+            })
+            .collect();
+        let mut sums = vec![(0i64, 0i64); width as usize * height as usize * num_channels];
+        for (position, channel, value) in fractal_values.into_iter().flatten() {
+            let pixel = (position.im as u32 / scale) as usize * width as usize + (position.re as u32 / scale) as usize;
+            let sum = &mut sums[pixel * num_channels + channel];
+            sum.0 += value as i64;
+            sum.1 += 1;
+        }
+
+        let mut metadata = wavelet_image.metadata;
+        metadata.width = width;
+        metadata.height = height;
+        let mut raster = RasterImage {
+            data: vec![0; width as usize * height as usize * metadata.colorspace.bytes_per_pixel()],
+            metadata,
+        };
+        for (index, (sum, count)) in sums.into_iter().enumerate() {
+            if count > 0 {
+                let pixel = index / num_channels;
+                let (x, y) = (pixel % width as usize, pixel / width as usize);
+                let mean = (2 * sum + count).div_euclid(2 * count) as i32;
+                raster.set_pixel(x as i32, y as i32, mean, index % num_channels);
+            }
+        }
+        raster
+    }
+
+    fn extract_values(
+        fractal: &Fractal,
+        num_channels: usize,
+        last_level: u8,
+        (width, height): (u32, u32),
+    ) -> Vec<(Complex<i32>, usize, i32)> {
+        let leaves_per_node = 1 << (fractal.depth - last_level);
+        let inside = |leaf: &&Complex<i32>| {
+            leaf.re >= 0 && leaf.im >= 0 && leaf.re < width as i32 && leaf.im < height as i32
+        };
+        let mut values = vec![];
+        for channel in 0..num_channels {
+            let mut low_pass_values = vec![None; 1 << (last_level + 1)];
+            low_pass_values[1] = fractal.coefficients[channel][0];
 
-            for level in 0..fractal.depth {
+            for level in 0..last_level {
                 for pos in 1 << level..1 << (level + 1) {
-                    if let Some(dif) = fractal.coefficients[channel][pos] {
-                        let right_subtree: i32 = low_pass_values[pos] - dif / 2;
+                    if let (Some(low_pass), Some(dif)) = (low_pass_values[pos], fractal.coefficients[channel][pos]) {
+                        let right_subtree: i32 = low_pass - dif / 2;
                         let left_subtree: i32 = dif + right_subtree;
-                        if level == fractal.depth - 1 {
-                            let left_pixel = fractal.image_positions[2 * pos];
-                            let right_pixel = fractal.image_positions[2 * pos + 1];
-                            self.set_pixel(left_pixel.re, left_pixel.im, left_subtree, channel);
-                            self.set_pixel(right_pixel.re, right_pixel.im, right_subtree, channel);
-                        } else {
-                            low_pass_values[2 * pos] = left_subtree;
-                            low_pass_values[2 * pos + 1] = right_subtree;
-                        }
+                        low_pass_values[2 * pos] = Some(left_subtree);
+                        low_pass_values[2 * pos + 1] = Some(right_subtree);
                     }
                 }
             }
+
+            // Nodes of the last level cover a contiguous run of leaves, pixels outside
+            // the image counted as zeros in their low-pass value
+            for pos in 1 << last_level..1 << (last_level + 1) {
+                if let Some(value) = low_pass_values[pos] {
+                    let leaves = &fractal.image_positions[pos * leaves_per_node..(pos + 1) * leaves_per_node];
+                    let covered = leaves.iter().filter(inside).count() as i32;
+                    let mean = if covered < leaves_per_node as i32 {
+                        (2 * value * leaves_per_node as i32 + covered).div_euclid(2 * covered.max(1))
+                    } else {
+                        value
+                    };
+                    values.extend(leaves.iter().filter(inside).map(|&leaf| (leaf, channel, mean)));
+                }
+            }
         }
+        values
     }
 }
 
+/*
+ * Side of the pixel blocks averaged into one preview pixel, two fractal levels
+ * split a block into four.
+ */
+pub fn get_preview_scale(depth: u8, max_level: u8) -> u32 {
+    1 << ((depth - max_level.saturating_add(1).min(depth)) / 2)
+}
+
+#[derive(Clone)]
 pub struct WaveletImage {
     pub metadata: ImageMetadata,
     pub fractal_lattice: HashMap<Complex<i32>, Fractal>,
     pub global_position_map: Vec<HashMap<Complex<i32>, Complex<i32>>>,
     pub sorted_lattice: [Vec<Complex<i32>>; BASE_FRAC_DEPTH as usize],
+    pub quantization_matrix: [i32; QUANTIZATION_LAYERS],
+    pub group_size: u32,
+    pub num_groups: usize,
 }
 
 impl WaveletImage {
@@ -395,7 +524,7 @@ impl WaveletImage {
                 0;
                 (metadata.width) as usize
                     * (metadata.height) as usize
-                    * metadata.colorspace.num_channels()
+                    * metadata.colorspace.bytes_per_pixel()
             ],
             metadata,
         };
@@ -407,67 +536,144 @@ impl WaveletImage {
             raster_image.metadata.width,
             raster_image.metadata.height,
             BASE_FRAC_DEPTH,
+            raster_image.metadata.variant,
         );
 
-        for (_, fractal) in fractal_lattice.iter_mut() {
-            fractal.extract_coefficients(&raster_image, fractal.depth);
-        }
         fractal_lattice
-            .retain(|_, frac| frac.coefficients.iter().all(|channel| channel[0].is_some()));
+            .par_iter_mut()
+            .for_each(|(_, fractal)| fractal.extract_coefficients(&raster_image, fractal.depth));
+        let num_channels = raster_image.metadata.colorspace.num_channels();
+        fractal_lattice.retain(|_, frac| {
+            frac.coefficients[..num_channels].iter().all(|channel| channel[0].is_some())
+        });
 
         let global_position_map = Self::get_global_position_map(&fractal_lattice);
-        let sorted_lattice = Self::sort_lattice(
-            &fractal_lattice,
-            &global_position_map,
-            raster_image.metadata.height,
-            raster_image.metadata.width,
-        );
+        let sorted_lattice = Self::sort_lattice(&global_position_map, raster_image.metadata.variant);
 
         WaveletImage {
             metadata: raster_image.metadata,
             global_position_map,
             fractal_lattice,
             sorted_lattice,
+            quantization_matrix: [1; QUANTIZATION_LAYERS],
+            group_size: 0,
+            num_groups: 1,
         }
     }
 
-    fn get_global_position_map(
-        fractal_lattice: &HashMap<Complex<i32>, Fractal>,
-    ) -> Vec<HashMap<Complex<i32>, Complex<i32>>> {
-        let mut position_map = vec![HashMap::new(); BASE_FRAC_DEPTH as usize];
+    /*
+     * Image of the same dimensions and variant shares the whole lattice, only
+     * coefficients have to be extracted again.
+     */
+    pub fn with_raster(&self, raster_image: RasterImage) -> WaveletImage {
+        let mut wavelet_image = self.clone();
+        wavelet_image
+            .fractal_lattice
+            .par_iter_mut()
+            .for_each(|(_, fractal)| fractal.extract_coefficients(&raster_image, fractal.depth));
+        wavelet_image.metadata = raster_image.metadata;
+        wavelet_image
+    }
 
-        for (center, frac) in fractal_lattice.iter() {
-            for level in 0..BASE_FRAC_DEPTH {
-                for position in &frac.image_positions[1 << level..(1 << level + 1)] {
-                    position_map[level as usize].insert(*position, *center);
-                }
+    /*
+     * Splits the lattice into groups of fractals whose centers share a cell of
+     * group_size pixels, groups are numbered in raster order of their cells.
+     * Group size of zero keeps the whole lattice in a single group.
+     */
+    pub fn assign_groups(&mut self, group_size: u32) {
+        let get_cell = |center: &Complex<i32>| {
+            if group_size == 0 {
+                Complex::new(0, 0)
+            } else {
+                Complex::new(
+                    center.re.div_euclid(group_size as i32),
+                    center.im.div_euclid(group_size as i32),
+                )
+            }
+        };
+
+        let mut cells: Vec<Complex<i32>> = self.fractal_lattice.keys().map(get_cell).collect();
+        cells.sort_by(utils::order_complex);
+        cells.dedup();
+
+        for (center, fractal) in self.fractal_lattice.iter_mut() {
+            let cell = get_cell(center);
+            fractal.group = cells.binary_search_by(|other| utils::order_complex(other, &cell)).unwrap();
+        }
+        self.group_size = group_size;
+        self.num_groups = cells.len();
+    }
+
+    /*
+     * Sorted lattice split by fractal groups, every group keeps the causal order of
+     * the whole image.
+     */
+    pub fn get_group_lattices(&self) -> Vec<[Vec<Complex<i32>>; BASE_FRAC_DEPTH as usize]> {
+        let mut group_lattices: Vec<[Vec<Complex<i32>>; BASE_FRAC_DEPTH as usize]> =
+            vec![Default::default(); self.num_groups];
+        for (level, plane) in self.sorted_lattice.iter().enumerate() {
+            for position in plane {
+                let parent_pos = &self.global_position_map[level][position];
+                let group = self.fractal_lattice[parent_pos].group;
+                group_lattices[group][level].push(*position);
             }
         }
+        group_lattices
+    }
 
-        position_map
+    /*
+     * Coefficients of one channel of every fractal keyed by its center, the form the
+     * context functions read neighbouring coefficients from.
+     */
+    pub fn get_channel_coefficients(&self, channel: usize) -> HashMap<Complex<i32>, &[Option<i32>]> {
+        self.fractal_lattice
+            .iter()
+            .map(|(center, fractal)| (*center, fractal.coefficients[channel].as_slice()))
+            .collect()
     }
 
-    fn fractal_divide(width: u32, height: u32, depth: u8) -> HashMap<Complex<i32>, Fractal> {
+    fn get_global_position_map(
+        fractal_lattice: &HashMap<Complex<i32>, Fractal>,
+    ) -> Vec<HashMap<Complex<i32>, Complex<i32>>> {
+        (0..BASE_FRAC_DEPTH)
+            .into_par_iter()
+            .map(|level| {
+                let mut level_map = HashMap::new();
+                for (center, frac) in fractal_lattice.iter() {
+                    for position in &frac.image_positions[1 << level..(1 << level + 1)] {
+                        level_map.insert(*position, *center);
+                    }
+                }
+                level_map
+            })
+            .collect()
+    }
+
+    fn fractal_divide(
+        width: u32,
+        height: u32,
+        depth: u8,
+        variant: FractalVariant,
+    ) -> HashMap<Complex<i32>, Fractal> {
         let mut fractal_lattice = HashMap::<Complex<i32>, Fractal>::new();
         let center = Complex::<i32>::new(width as i32 / 2, height as i32 / 2);
         let mut to_add = VecDeque::<Complex<i32>>::new();
         to_add.push_back(center);
 
-        let mut boundary = VecDeque::<Complex<i32>>::new();
+        let mut visited = HashSet::<Complex<i32>>::new();
+        visited.insert(center);
+        let image_region = Region { x: 0, y: 0, width, height };
 
+        // Fractals centered outside of the image may still cover its corners, so the
+        // lattice grows as long as a fractal contains at least one pixel of the image
         while let Some(position) = to_add.pop_front() {
-            if position.re < 0
-                || position.im < 0
-                || position.re > width as i32
-                || position.im > height as i32
-            {
-                boundary.push_back(position);
+            let fractal = Fractal::new(depth, position, variant);
+            if !fractal.intersects(&image_region) {
                 continue;
             }
 
-            let fractal = Fractal::new(depth, position);
             for neighbour in fractal.get_neighbour_locations() {
-                if !fractal_lattice.contains_key(&neighbour) && !to_add.contains(&neighbour) {
+                if visited.insert(neighbour) {
                     to_add.push_back(neighbour);
                 }
             }
@@ -475,11 +681,6 @@ impl WaveletImage {
             fractal_lattice.insert(position, fractal);
         }
 
-        while let Some(position) = boundary.pop_front() {
-            let boundary_fractal = Fractal::new(depth, position);
-            fractal_lattice.insert(position, boundary_fractal);
-        }
-
         fractal_lattice
     }
 
@@ -487,220 +688,63 @@ impl WaveletImage {
         &self.sorted_lattice
     }
 
-    fn is_pos_in_row_boundary(
-        pos: &Complex<i32>,
-        row_dir: &Complex<i32>,
-        min_real: i32,
-        max_real: i32,
-        min_imag: i32,
-        max_imag: i32,
-    ) -> bool {
-        if row_dir.re.abs() > row_dir.im.abs() {
-            pos.im >= min_imag && pos.im <= max_imag
-        } else {
-            pos.re >= min_real && pos.re <= max_real
-        }
-    }
-
-    fn scan_level(
-        level: u8,
-        depth: u8,
-        center: Complex<i32>,
-        global_position_map: &HashMap<Complex<i32>, Complex<i32>>,
-        min_real: i32,
-        max_real: i32,
-        min_imag: i32,
-        max_imag: i32,
-    ) -> Vec<Complex<i32>> {
-        let neighbour_vectors = Fractal::get_nearby_vectors(BASE_FRAC_DEPTH - level);
-        let row_dir = neighbour_vectors[3];
-        let rev_row_dir = neighbour_vectors[0];
-
-        let col_dir = neighbour_vectors[1];
-        let rev_col_dir = neighbour_vectors[4];
-        //find first
-
-        let mut first = center;
-
-        let mut layer_seven_mod = 0;
-        if !global_position_map.contains_key(&(center + rev_row_dir))
-            && global_position_map.contains_key(&(center + Complex::new(-1, -1)))
-        {
-            layer_seven_mod = 1;
-        }
-        let mut last_seen = first;
-
-        while (global_position_map.contains_key(&first)) {
-            last_seen = first;
-            if depth - level != 2 {
-                first += rev_row_dir;
-            } else {
-                if layer_seven_mod % 2 == 0 {
-                    first += rev_row_dir
-                } else {
-                    first += Complex::new(-1, -1);
-                }
-                layer_seven_mod += 1;
-            }
+    /*
+     * Direction in which positions of a level are scanned. Left, up-left and up-right
+     * neighbours used for context modeling all lie strictly before the position along it,
+     * so the decoder has them available. It is the negated bisector of the two outermost
+     * neighbour vectors, which keeps every neighbour at an angle larger than right angle.
+     */
+    fn get_scan_direction(level: u8, variant: FractalVariant) -> Complex<f64> {
+        let depth = BASE_FRAC_DEPTH - level;
+        let vectors = Fractal::get_nearby_vectors(variant, depth);
+        let mut causal = vec![vectors[4], vectors[5], vectors[0]];
+        if variant == FractalVariant::TameTwindragon && depth == 2 {
+            causal.push(Complex::new(-1, -1));
+            causal.push(Complex::new(-1, -1) + vectors[4]);
         }
 
-        // Find first row
-        loop {
-            let mut column_forward = first;
-            let mut column_backward = first;
-            let mut empty_column = true;
-            while (column_forward.im <= max_imag && column_forward.im >= min_imag)
-                || (column_backward.im <= max_imag && column_backward.im >= min_imag)
-                || (column_forward.re <= max_real && column_forward.re >= min_real)
-                || (column_backward.re <= max_real && column_backward.re >= min_real)
-            {
-                column_forward += col_dir;
-                column_backward += rev_col_dir;
-                if global_position_map.contains_key(&column_forward) {
-                    last_seen = column_forward;
-                    empty_column = false;
-                    break;
-                }
-                if global_position_map.contains_key(&column_backward) {
-                    last_seen = column_backward;
-                    empty_column = false;
-                    break;
-                }
-            }
-            if empty_column {
-                first = last_seen;
-                break;
-            } else {
-                if depth - level != 2 {
-                    first += rev_row_dir;
-                } else {
-                    if layer_seven_mod % 2 == 0 {
-                        first += rev_row_dir
-                    } else {
-                        first += Complex::new(-1, -1);
-                    }
-                    layer_seven_mod += 1;
-                }
-            }
-        }
-
-        // Scanning backwards find first column
-        while (first.im <= max_imag
-            && first.im >= min_imag
-            && first.re <= max_real
-            && first.re >= min_real)
-        {
-            first += rev_col_dir;
-            if global_position_map.contains_key(&first) {
-                last_seen = first;
-            }
-        }
-        first = last_seen;
-        layer_seven_mod = 1;
-
-        // Fill plane in sorted order
-        let mut plane: Vec<Complex<i32>> = Vec::new();
-        'outer: loop {
-            let mut cnt = 0;
-            let mut scan = first;
-            loop {
-                if global_position_map.contains_key(&scan) {
-                    plane.push(scan);
-                    cnt += 1;
-                }
-                if ((scan.im > max_imag || scan.im < min_imag)
-                    || (col_dir.im == 0 && (scan.re > max_real || scan.re < min_real)))
-                {
-                    break;
-                }
-                scan += col_dir;
-            }
-
-            if depth - level != 2 {
-                first += row_dir;
-            } else {
-                if layer_seven_mod % 2 == 0 {
-                    first += Complex::new(1, 1);
-                } else {
-                    first += row_dir
-                }
-                layer_seven_mod += 1;
-            }
-            while (!global_position_map.contains_key(&first)) {
-                first += col_dir;
-                if (!Self::is_pos_in_row_boundary(
-                    &first, &row_dir, min_real, max_real, min_imag, max_imag,
-                )) {
-                    break 'outer;
-                }
-            }
-            if global_position_map.contains_key(&first) {
-                last_seen = first;
-                while (first.im <= max_imag
-                    && first.im >= min_imag
-                    && first.re <= max_real
-                    && first.re >= min_real)
-                {
-                    first += rev_col_dir;
-
-                    if global_position_map.contains_key(&first) {
-                        last_seen = first;
-                    }
-                }
-                first = last_seen;
-            }
-        }
-        plane
+        let units: Vec<Complex<f64>> = causal
+            .iter()
+            .map(|v| {
+                let v = Complex::new(v.re as f64, v.im as f64);
+                v / v.norm()
+            })
+            .collect();
+
+        let (first, second) = units
+            .iter()
+            .enumerate()
+            .flat_map(|(i, a)| units[i + 1..].iter().map(move |b| (*a, *b)))
+            .min_by(|(a1, b1), (a2, b2)| {
+                let dot1 = a1.re * b1.re + a1.im * b1.im;
+                let dot2 = a2.re * b2.re + a2.im * b2.im;
+                dot1.total_cmp(&dot2)
+            })
+            .unwrap();
+
+        -(first + second)
     }
 
-    // TODO: Simplify this logic from hell
     fn sort_lattice(
-        fractal_lattice: &HashMap<Complex<i32>, Fractal>,
         global_position_map: &Vec<HashMap<Complex<i32>, Complex<i32>>>,
-        height: u32,
-        width: u32,
+        variant: FractalVariant,
     ) -> [Vec<Complex<i32>>; BASE_FRAC_DEPTH as usize] {
-        let keys: Vec<Complex<i32>> = fractal_lattice.keys().cloned().collect();
-        let depth = fractal_lattice[&keys[0]].depth;
-
-        let min_real = global_position_map[BASE_FRAC_DEPTH as usize - 1]
-            .keys()
-            .min_by_key(|x| x.re)
-            .unwrap()
-            .re;
-        let max_real = global_position_map[BASE_FRAC_DEPTH as usize - 1]
-            .keys()
-            .max_by_key(|x| x.re)
-            .unwrap()
-            .re;
-        let min_imag = global_position_map[BASE_FRAC_DEPTH as usize - 1]
-            .keys()
-            .min_by_key(|x| x.im)
-            .unwrap()
-            .im;
-        let max_imag = global_position_map[BASE_FRAC_DEPTH as usize - 1]
-            .keys()
-            .max_by_key(|x| x.im)
-            .unwrap()
-            .im;
-
         let mut sorted_fractalwise: [Vec<Complex<i32>>; BASE_FRAC_DEPTH as usize] = Default::default();
-        let center = Complex::<i32>::new(width as i32 / 2, height as i32 / 2);
 
-        for level in (0..BASE_FRAC_DEPTH) {
-            let plane = Self::scan_level(
-                level,
-                depth,
-                center,
-                &global_position_map[level as usize],
-                min_real,
-                max_real,
-                min_imag,
-                max_imag,
-            );
-            assert_eq!(plane.len(), fractal_lattice.len() * (1 << level));
-            sorted_fractalwise[level as usize] = plane;
-        }
+        sorted_fractalwise
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(level, plane)| {
+                let direction = Self::get_scan_direction(level as u8, variant);
+                *plane = global_position_map[level].keys().cloned().collect();
+                plane.sort_by(|a, b| {
+                    let key_a = direction.re * a.re as f64 + direction.im * a.im as f64;
+                    let key_b = direction.re * b.re as f64 + direction.im * b.im as f64;
+                    key_a
+                        .total_cmp(&key_b)
+                        .then_with(|| utils::order_complex(a, b))
+                });
+            });
         sorted_fractalwise
     }
 }
@@ -709,11 +753,46 @@ pub fn encode(
     raster_image: RasterImage,
     _encoder_opts: &EncoderOpts,
 ) -> Result<WaveletImage, String> {
-    Ok(WaveletImage::from_raster(raster_image))
+    let mut wavelet_image = WaveletImage::from_raster(raster_image);
+    wavelet_image.assign_groups(FRACTAL_GROUP_SIZE);
+    Ok(wavelet_image)
 }
 
 pub fn decode(wavelet_image: WaveletImage) -> Result<RasterImage, String> {
-    Ok(RasterImage::from_wavelet(wavelet_image))
+    Ok(RasterImage::from_wavelet(wavelet_image, None))
+}
+
+pub fn decode_region(wavelet_image: WaveletImage, region: &Region) -> Result<RasterImage, String> {
+    RasterImage::from_wavelet(wavelet_image, Some(region))
+        .crop(region)
+        .map_err(|reason| reason.to_string())
+}
+
+pub fn decode_preview(wavelet_image: WaveletImage, max_level: u8) -> Result<RasterImage, String> {
+    Ok(RasterImage::preview_from_wavelet(wavelet_image, max_level, None))
+}
+
+/*
+ * The region is given in pixels of the full image, the preview is cropped to
+ * every block it touches.
+ */
+pub fn decode_preview_region(
+    wavelet_image: WaveletImage,
+    max_level: u8,
+    region: &Region,
+) -> Result<RasterImage, String> {
+    let depth = wavelet_image.fractal_lattice.values().next().map_or(0, |fractal| fractal.depth);
+    let scale = get_preview_scale(depth, max_level);
+    let (x, y) = (region.x / scale, region.y / scale);
+    let scaled = Region {
+        x,
+        y,
+        width: (region.x + region.width).div_ceil(scale) - x,
+        height: (region.y + region.height).div_ceil(scale) - y,
+    };
+    RasterImage::preview_from_wavelet(wavelet_image, max_level, Some(region))
+        .crop(&scaled)
+        .map_err(|reason| reason.to_string())
 }
 
 #[cfg(test)]
@@ -736,4 +815,79 @@ mod test {
 
         //let coefficients = extract_coefficients(&img, center, depth - 1);
     }
+
+    #[test]
+    fn lossless_round_trip_at_odd_sizes() {
+        use crate::test_images::{self, noisy_gradient};
+
+        for (width, height) in [(37, 23), (64, 48), (101, 77)] {
+            // Noise makes every fractal carry nonzero coefficients
+            let data = noisy_gradient(width, height, 15);
+            let decoded = test_images::round_trip(EncoderOpts::default(), width, height, &data);
+            assert!(decoded.data == data, "{width}x{height}");
+        }
+    }
+
+    fn assert_variant_is_lossless(variant: FractalVariant) {
+        use crate::test_images::{self, noisy_gradient, saturated};
+
+        for (width, height) in [(37, 23), (101, 77)] {
+            for data in [noisy_gradient(width, height, 15), saturated(width, height)] {
+                let opts = EncoderOpts { variant, ..Default::default() };
+                let decoded = test_images::round_trip(opts, width, height, &data);
+                assert_eq!(decoded.metadata.variant, variant);
+                assert!(decoded.data == data, "{variant:?} {width}x{height}");
+            }
+        }
+    }
+
+    #[test]
+    fn twindragon_round_trip() {
+        assert_variant_is_lossless(FractalVariant::Twindragon);
+    }
+
+    #[test]
+    fn boxes_round_trip() {
+        assert_variant_is_lossless(FractalVariant::Boxes);
+    }
+
+    #[test]
+    fn lattice_tiles_image() {
+        // The removed CENTERS table stopped at 4243 pixels wide
+        for (variant, width, height) in [
+            (FractalVariant::TameTwindragon, 70, 45),
+            (FractalVariant::TameTwindragon, 4300, 3),
+            (FractalVariant::Twindragon, 70, 45),
+            (FractalVariant::Boxes, 70, 45),
+        ] {
+            let mut metadata = ImageMetadata::new(height, width);
+            metadata.variant = variant;
+            let image = WaveletImage::from_metadata(metadata);
+
+            let mut covered = HashMap::<Complex<i32>, usize>::new();
+            for fractal in image.fractal_lattice.values() {
+                for pos in &fractal.image_positions[1 << fractal.depth..] {
+                    *covered.entry(*pos).or_default() += 1;
+                }
+            }
+            for x in 0..width as i32 {
+                for y in 0..height as i32 {
+                    assert_eq!(covered.get(&Complex::new(x, y)), Some(&1), "{variant:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn group_lattices_partition_image() {
+        let mut image = WaveletImage::from_metadata(ImageMetadata::new(300, 600));
+        image.assign_groups(FRACTAL_GROUP_SIZE);
+        assert!(image.num_groups > 1);
+
+        let group_lattices = image.get_group_lattices();
+        for level in 0..BASE_FRAC_DEPTH as usize {
+            let total: usize = group_lattices.iter().map(|lattice| lattice[level].len()).sum();
+            assert_eq!(total, image.sorted_lattice[level].len());
+        }
+    }
 }
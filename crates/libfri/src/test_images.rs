@@ -0,0 +1,54 @@
+use crate::decoder::{DecoderOpts, FRIDecoder};
+use crate::encoder::{EncoderOpts, FRIEncoder};
+use crate::images::{ColorSpace, ImageMetadata, RasterImage};
+
+/*
+ * Synthetic RGB images shared by the tests. Gradients are smooth and compress
+ * to almost nothing, noise keeps every residual context busy and saturated
+ * blocks push the chroma of the color transform to the ends of its range.
+ */
+pub fn gradient(width: u32, height: u32) -> Vec<u8> {
+    (0..width * height * 3)
+        .map(|i| ((i / 3 % width + i / 3 / width * 2 + i % 3 * 40) % 256) as u8)
+        .collect()
+}
+
+pub fn noisy_gradient(width: u32, height: u32, amplitude: u32) -> Vec<u8> {
+    let mut state = 0x2545_F491u32;
+    gradient(width, height)
+        .into_iter()
+        .map(|value| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (value as u32 + state % (amplitude + 1)).min(255) as u8
+        })
+        .collect()
+}
+
+pub fn saturated(width: u32, height: u32) -> Vec<u8> {
+    const COLORS: [[u8; 3]; 4] = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 0, 255]];
+    (0..width * height)
+        .flat_map(|i| COLORS[((i % width / 8 + i / width / 8) % 4) as usize])
+        .collect()
+}
+
+pub fn raster(width: u32, height: u32, data: Vec<u8>) -> RasterImage {
+    RasterImage { metadata: ImageMetadata::new(height, width), data }
+}
+
+/*
+ * Tests running the whole codec encode RGB data with their options and decode it
+ * with the default ones.
+ */
+pub fn encode(opts: EncoderOpts, width: u32, height: u32, data: &[u8]) -> Vec<u8> {
+    FRIEncoder::new(opts).unwrap().encode(data.to_vec(), height, width, ColorSpace::RGB).unwrap()
+}
+
+pub fn decode(encoded: Vec<u8>) -> RasterImage {
+    FRIDecoder::new(DecoderOpts::default()).unwrap().decode(encoded).unwrap()
+}
+
+pub fn round_trip(opts: EncoderOpts, width: u32, height: u32, data: &[u8]) -> RasterImage {
+    decode(encode(opts, width, height, data))
+}
@@ -31,18 +31,22 @@ pub fn order_complex<T: std::cmp::PartialEq + std::cmp::PartialOrd>(
     }
 }
 
-pub fn pack_signed(k: i32) -> u32 {
-    if k >= 0 {
-        2 * k as u32
-    } else {
-        (-2 * k - 1) as u32
+pub fn write_leb128(mut value: u64, out: &mut Vec<u8>) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
     }
 }
 
+pub fn pack_signed(k: i32) -> u32 {
+    ((k << 1) ^ (k >> 31)) as u32
+}
+
 pub fn unpack_signed(k: u32) -> i32 {
-    if k % 2 == 0 {
-        (k / 2) as i32
-    } else {
-        (k + 1) as i32 / -2
-    }
+    (k >> 1) as i32 ^ -((k & 1) as i32)
 }